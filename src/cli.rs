@@ -8,12 +8,53 @@
 
 //! Command line interface.
 
-use crate::config::Config;
-use crate::download::{download_file, fetch_sync_info, to_human_size, EpisodeDownload};
-use clap::{Parser, Subcommand};
+use crate::config::{
+    add_podcasts, find_config_path, find_download_dir, parse_feed_list, remove_podcasts,
+    update_download_dir, validate_feed_url, Config, PodcastConfig,
+};
+use crate::context::{build_client, build_proxy_clients, AppContext};
+use crate::discover::discover_feeds;
+use crate::doctor;
+use crate::download::{
+    download_file, download_sidecar, fetch_channels, fetch_sync_info, is_credentials_rejected,
+    item_guid, podcast_dir_name, retrieve_content_length, to_human_size, unix_timestamp,
+    verify_integrity, ConcurrencyController, EpisodeDownload, FileRequest, HostLimiter,
+    PartialFile, ProgressDisplay, SkippedEpisodes, SyncOptions, CONCURRENCY_SAMPLE_INTERVAL,
+};
+use crate::events::Event;
+use crate::extension::item_show_notes;
+use crate::health::{feed_health, render_health};
+use crate::library::{
+    ensure_writable, existing_sidecars, find_duplicates, find_evictions, find_expired_files,
+    list_files, migrate, remove_empty_dirs,
+};
+use crate::metadata::EpisodeMeta;
+use crate::notify::{send_notifications, DownloadedEpisode, FailedEpisode, SyncReport};
+use crate::opml::{normalize_feed_url, parse_opml};
+use crate::playlist::write_playlists;
+use crate::queue::SavedQueue;
+use crate::state::{EpisodeState, PendingEpisode, State, StateStore};
+use crate::status::{
+    render_episodes, render_status, render_total_size, total_download_size, StatusFormat,
+};
+use crate::tags::{is_taggable, write_tags, EpisodeTags};
+use crate::trace;
+use crate::units::{parse_date, parse_duration, parse_selection};
+use crate::version::{fetch_latest_version, is_newer, CURRENT_VERSION};
+use clap::{Args, Parser, Subcommand};
+use colored::Colorize;
+use futures::future::{AbortHandle, Abortable};
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
-use std::path::PathBuf;
+use reqwest::{Client, Url};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
 /// A fictional versioning CLI
 #[derive(Debug, Parser)]
@@ -22,16 +63,291 @@ use std::path::PathBuf;
 struct Cli {
     #[clap(required = false, long = "config", parse(from_os_str))]
     config: Option<PathBuf>,
+    /// Base directory for the config file and the state, instead of the default locations.
+    ///
+    /// The config file is read from `config.toml` in this directory (unless `--config` is given)
+    /// and the state is stored in its `state` subdirectory (unless `state_dir` is set in the
+    /// config).
+    #[clap(long, parse(from_os_str))]
+    config_dir: Option<PathBuf>,
+    /// Plain text file with additional feed URLs (one per line, optionally followed by a tab and
+    /// a title).
+    #[clap(long, parse(from_os_str))]
+    feed_file: Option<PathBuf>,
+    /// Print additional information.
+    #[clap(short, long)]
+    verbose: bool,
+    /// Print every HTTP request and response (with credentials redacted) to stderr.
+    #[clap(long)]
+    trace_http: bool,
+    /// When to use colored output.
+    ///
+    /// With `auto`, colors are disabled if stdout is not a terminal or `NO_COLOR` is set.
+    #[clap(long, arg_enum, default_value = "auto")]
+    color: ColorChoice,
     #[clap(subcommand)]
     command: Commands,
 }
 
+/// Options that change which episodes are downloaded (or listed by `status`).
+#[derive(Args, Clone, Debug, Default, PartialEq)]
+struct PlanArgs {
+    /// Download episodes again even if they already exist (overwrites existing files).
+    #[clap(long)]
+    force_redownload: bool,
+    /// Only download episodes that were not in the feed during the previous sync, even if older
+    /// episodes have not been downloaded yet.
+    #[clap(long, conflicts_with = "force-redownload")]
+    only_new: bool,
+    /// Download at most this many episodes from all feeds combined, preferring the newest ones.
+    #[clap(long)]
+    max_episodes_total: Option<usize>,
+    /// Remove known analytics redirectors from enclosure URLs (same as `strip_tracking` in the
+    /// config).
+    #[clap(long)]
+    strip_tracking: bool,
+    /// Only download episodes that were published after the start of the last sync in which no
+    /// feed and no download failed.
+    ///
+    /// Episodes without publication date are downloaded as usual.
+    #[clap(long)]
+    since_last_run: bool,
+    /// Fetch all feeds, even if their announced update interval has not elapsed yet.
+    #[clap(long)]
+    ignore_ttl: bool,
+    /// Fetch all feeds, even if their update interval has not elapsed or they are skipped after
+    /// failing repeatedly.
+    ///
+    /// A feed that failed three times in a row is skipped for an hour after the last failure,
+    /// and for twice as long after every further failure (up to a week).
+    #[clap(long)]
+    force: bool,
+}
+
+/// Options of the commands that download episodes.
+#[derive(Args, Clone, Debug, Default, PartialEq)]
+struct SyncArgs {
+    #[clap(flatten)]
+    plan_args: PlanArgs,
+    /// Don't show progress bars, only print the summary at the end (e.g. for logs).
+    #[clap(long)]
+    summary_only: bool,
+    /// Fetch the feeds and download the episodes one at a time, in order (e.g. for debugging).
+    ///
+    /// This overrides `max_parallel_feeds`, `max_parallel_downloads` and
+    /// `max_connections_per_host` from the config.
+    #[clap(long)]
+    no_parallel: bool,
+    /// Start with a single download and add parallel downloads while the combined throughput
+    /// improves, up to `max_parallel_downloads` from the config.
+    ///
+    /// Fewer downloads are run in parallel again if the throughput does not improve or downloads
+    /// fail.
+    #[clap(long, conflicts_with = "no-parallel")]
+    concurrency_auto: bool,
+    /// Write a JSON report of each sync (downloaded and failed episodes) to this file.
+    #[clap(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+}
+
+/// Possible values of the `--color` option.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum ColorChoice {
+    /// Detect automatically if colors should be used.
+    Auto,
+    /// Always use colors.
+    Always,
+    /// Never use colors.
+    Never,
+}
+
 #[derive(Debug, Subcommand, PartialEq)]
 enum Commands {
     /// Show the current status.
-    Status,
+    Status {
+        #[clap(flatten)]
+        plan_args: PlanArgs,
+        /// Output format.
+        #[clap(long, arg_enum, default_value = "table")]
+        format: StatusFormat,
+        /// Also list the episodes that have already been downloaded.
+        #[clap(long)]
+        include_downloaded: bool,
+    },
+    /// List the episodes of a single podcast and whether they have been downloaded.
+    Episodes {
+        /// Feed URL of the podcast (as in the config file).
+        url: String,
+        /// List at most this many episodes (all by default).
+        ///
+        /// This overrides the episode limit of the podcast, so it can be used to check which
+        /// episodes a higher limit would download.
+        #[clap(long)]
+        limit: Option<usize>,
+        /// Print the episodes as JSON.
+        #[clap(long)]
+        json: bool,
+    },
     /// Fetch the latest podcasts.
-    Sync,
+    Sync {
+        #[clap(flatten)]
+        sync_args: SyncArgs,
+        /// Choose which of the new episodes to download before downloading them.
+        ///
+        /// This is ignored if stdin or stdout is not a terminal.
+        #[clap(long)]
+        interactive: bool,
+        /// Print each event (e.g. a finished download) as JSON object on a separate line instead
+        /// of showing progress bars.
+        #[clap(long, conflicts_with = "interactive")]
+        json_lines: bool,
+        /// Only determine the sizes of the new episodes and record them in the state, without
+        /// downloading anything.
+        ///
+        /// The episodes are downloaded by the next sync without this flag.
+        #[clap(long, conflicts_with_all = &["interactive", "json-lines"])]
+        resolve_only: bool,
+        /// Continue the downloads of an interrupted sync without fetching the feeds again.
+        ///
+        /// Each sync saves its planned downloads in the state directory, and the episodes that
+        /// have not been downloaded yet are downloaded from that list again. Partially downloaded
+        /// files are downloaded from the start.
+        #[clap(long, conflicts_with = "resolve-only")]
+        resume: bool,
+        /// Cancel all downloads when the first one fails and exit with status 1 (e.g. in CI).
+        ///
+        /// By default, the remaining episodes are downloaded and the failures are reported at
+        /// the end.
+        #[clap(long, conflicts_with = "resolve-only")]
+        fail_fast: bool,
+    },
+    /// Fetch the latest podcasts repeatedly until SIGINT or SIGTERM is received.
+    ///
+    /// The announced update intervals of the feeds are respected, so feeds that did not change
+    /// are not fetched on every run.
+    Daemon {
+        #[clap(flatten)]
+        sync_args: SyncArgs,
+        /// Time to wait between two runs (e.g. `30m` or `6h`).
+        #[clap(long, parse(try_from_str = parse_duration), required_unless_present = "once")]
+        interval: Option<Duration>,
+        /// Only run a single sync and exit afterwards (e.g. when started by cron).
+        #[clap(long)]
+        once: bool,
+    },
+    /// Move all downloaded podcasts to a new directory and update the config.
+    Migrate {
+        /// The new download directory.
+        #[clap(parse(from_os_str))]
+        new_dir: PathBuf,
+    },
+    /// Check the environment and show hints for fixing problems.
+    Doctor,
+    /// Print the version.
+    Version {
+        /// Also check if a newer version has been published on crates.io.
+        #[clap(long)]
+        check_updates: bool,
+    },
+    /// Add podcast subscriptions to the config file.
+    Add {
+        /// Feed URL of the podcast.
+        #[clap(required_unless_present = "stdin")]
+        url: Option<String>,
+        /// Title of the podcast (overrides the title from the feed).
+        #[clap(long, conflicts_with = "stdin")]
+        title: Option<String>,
+        /// Read feed URLs from stdin (one per line, optionally followed by a tab and a title).
+        ///
+        /// Blank lines and lines starting with `#` are skipped.
+        #[clap(long)]
+        stdin: bool,
+        /// Fetch the feeds of podcasts without a title and store the title from the feed.
+        ///
+        /// This keeps the directory names stable even if the feed title changes later. Feeds that
+        /// cannot be fetched are added without a title.
+        #[clap(long)]
+        fetch_titles: bool,
+    },
+    /// Compare the subscriptions with an OPML file (e.g. exported from another podcast app).
+    ///
+    /// Feeds are matched by their URL, ignoring the scheme and trailing slashes.
+    Diff {
+        /// Path of the OPML file.
+        #[clap(parse(from_os_str))]
+        opml: PathBuf,
+        /// Add the feeds that are only in the OPML file to the config file.
+        #[clap(long)]
+        apply: bool,
+        /// Also remove the feeds that are not in the OPML file from the config file.
+        #[clap(long, requires = "apply")]
+        prune: bool,
+    },
+    /// Find the podcast feed of a website and add it to the config file.
+    ///
+    /// If the website links multiple feeds, they are listed instead.
+    Discover {
+        /// URL of the website.
+        url: String,
+    },
+    /// Delete old episodes from the download directory.
+    Clean {
+        /// Delete episodes whose files are older than this (e.g. `30d`, `12h` or `2w`).
+        #[clap(long, parse(try_from_str = parse_duration))]
+        max_age: Duration,
+        /// Only show which files would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Delete files from the download directory that have the same contents as another file.
+    ///
+    /// Of each group of identical files, the one that is recorded in the state is kept.
+    /// Additional files (e.g. chapters) of the deleted files are deleted as well.
+    Dedupe {
+        /// Only show which files would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Remove empty podcast directories from the download directory.
+    Tidy {
+        /// Also remove directories that only contain hidden files (which are deleted).
+        #[clap(long)]
+        aggressive: bool,
+    },
+    /// Download a single episode again, e.g. because the file is corrupted.
+    ///
+    /// The existing file is overwritten.
+    Redownload {
+        /// GUID of the episode.
+        guid: String,
+    },
+    /// Print the loaded config (with credentials redacted).
+    Config {
+        /// Fill in the default values of all settings that are not set.
+        #[clap(long)]
+        effective: bool,
+    },
+    /// Show which feeds have been failing repeatedly or stopped publishing episodes.
+    Health {
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Write ID3 tags with the current metadata from the feeds to the downloaded MP3 files.
+    ///
+    /// Files are matched to the feed items by the GUID in the state or by their file name. Files
+    /// that cannot be matched are skipped.
+    Retag {
+        /// Only show which files would be tagged.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Remove state entries of podcasts that are no longer in the config file.
+    PruneState {
+        /// Also remove entries of episodes whose files no longer exist.
+        #[clap(long)]
+        remove_missing: bool,
+    },
 }
 
 /// Main method.
@@ -39,83 +355,2098 @@ enum Commands {
 pub async fn main() {
     let args = Cli::parse();
 
-    let config = if let Some(config_path) = &args.config {
-        Config::from_path(config_path)
-    } else {
-        Config::from_default_path()
+    match args.color {
+        ColorChoice::Auto => (),
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+    }
+    if args.trace_http {
+        trace::enable();
+    }
+
+    let config_path = match (args.config, &args.config_dir) {
+        (Some(config_path), _) => Ok(config_path),
+        (None, Some(config_dir)) => Ok(config_dir.join("config.toml")),
+        (None, None) => find_config_path(),
+    };
+
+    if let Commands::Version { check_updates } = args.command {
+        print_version(check_updates).await;
+        return;
+    }
+
+    if args.command == Commands::Doctor {
+        if !doctor::run(config_path).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let sync_args = match &args.command {
+        Commands::Sync { sync_args, .. } | Commands::Daemon { sync_args, .. } => sync_args.clone(),
+        Commands::Status { plan_args, .. } => SyncArgs {
+            plan_args: plan_args.clone(),
+            ..SyncArgs::default()
+        },
+        _ => SyncArgs::default(),
+    };
+    let plan_args = &sync_args.plan_args;
+
+    let config_path = match config_path {
+        Ok(config_path) => config_path,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to find the config file: {}", err).red()
+            );
+            std::process::exit(1);
+        }
+    };
+    let mut config = match Config::from_path(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to load {:?}: {}", config_path, err).red()
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Some(feed_file) = &args.feed_file {
+        if let Err(err) = config.merge_feed_list(feed_file) {
+            eprintln!(
+                "{}",
+                format!("Failed to load feed file {:?}: {}", feed_file, err).red()
+            );
+            std::process::exit(1);
+        }
+    }
+    if plan_args.strip_tracking {
+        config.strip_tracking = Some(true);
+    }
+    if sync_args.no_parallel {
+        config.max_parallel_feeds = Some(1);
+        config.max_parallel_downloads = Some(1);
+        config.max_connections_per_host = Some(1);
+    }
+    if let (None, Some(config_dir)) = (&config.state_dir, &args.config_dir) {
+        config.state_dir = Some(config_dir.join("state"));
+    }
+
+    if let Commands::Config { effective } = args.command {
+        print_config(&config, effective);
+        return;
+    }
+
+    // The default download directory is created on demand, while a missing custom directory
+    // might indicate a typo or an unmounted drive.
+    if !config.download_dir.exists()
+        && find_download_dir().is_ok_and(|default_dir| default_dir == config.download_dir)
+    {
+        if let Err(err) = std::fs::create_dir_all(&config.download_dir) {
+            println!(
+                "{}",
+                format!(
+                    "Failed to create download directory {:?}: {}",
+                    config.download_dir, err
+                )
+                .red()
+            );
+            return;
+        }
     }
-    .unwrap();
 
     if !config.download_dir.is_dir() {
         println!(
-            "Download directory does not exist: {:?}",
-            config.download_dir
+            "{}",
+            format!(
+                "Download directory does not exist: {:?}",
+                config.download_dir
+            )
+            .red()
         );
         return;
     }
 
-    let max_jobs = config.max_parallel_downloads.unwrap_or(5usize);
-    let files_to_download: Vec<EpisodeDownload> =
-        fetch_sync_info(config.download_dir, config.podcast, max_jobs).await;
+    let clients =
+        build_client(&config).and_then(|client| Ok((client, build_proxy_clients(&config)?)));
+    let (client, proxy_clients) = match clients {
+        Ok(clients) => clients,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to set up the HTTP client: {}", err).red()
+            );
+            std::process::exit(1);
+        }
+    };
+    let context = match AppContext::new(client, proxy_clients, config_path, config) {
+        Ok(context) => context,
+        Err(err) => {
+            eprintln!("{}", err.to_string().red());
+            std::process::exit(1);
+        }
+    };
 
-    println!();
-    if files_to_download.is_empty() {
-        println!("Nothing to do.");
+    let options = SyncOptions {
+        verbose: args.verbose,
+        force_redownload: plan_args.force_redownload,
+        only_new: plan_args.only_new,
+        max_episodes_total: plan_args.max_episodes_total,
+        guid: None,
+        feed_url: None,
+        episode_limit: None,
+        include_downloaded: matches!(
+            args.command,
+            Commands::Status {
+                include_downloaded: true,
+                ..
+            }
+        ),
+        ignore_ttl: plan_args.ignore_ttl,
+        resolve_only: matches!(
+            args.command,
+            Commands::Sync {
+                resolve_only: true,
+                ..
+            }
+        ),
+        force: plan_args.force,
+        json_lines: matches!(
+            args.command,
+            Commands::Sync {
+                json_lines: true,
+                ..
+            }
+        ),
+        summary_only: sync_args.summary_only,
+        report_path: sync_args.report.clone(),
+        resume: matches!(args.command, Commands::Sync { resume: true, .. }),
+        concurrency_auto: sync_args.concurrency_auto,
+        since_last_run: plan_args.since_last_run,
+        fail_fast: matches!(
+            args.command,
+            Commands::Sync {
+                fail_fast: true,
+                ..
+            }
+        ),
+    };
+    match args.command {
+        Commands::Status { format, .. } => status(&context, format, &options).await,
+        Commands::Episodes { url, limit, json } => {
+            let options = SyncOptions {
+                feed_url: Some(url),
+                episode_limit: Some(limit.unwrap_or(0)),
+                include_downloaded: true,
+                force: true,
+                ..options
+            };
+            episodes(&context, &options, json).await;
+        }
+        Commands::Sync {
+            resolve_only: true, ..
+        } => resolve_only(&context, &options).await,
+        Commands::Sync { interactive, .. } => {
+            if !interactive {
+                startup_jitter(&context.config, !options.json_lines).await;
+            }
+            let report = sync(&context, &options, interactive).await;
+            notify(&context, &report).await;
+            if report.out_of_space || (options.fail_fast && report.failed > 0) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Daemon { interval, once, .. } => {
+            startup_jitter(&context.config, !options.json_lines).await;
+            match interval.filter(|_| !once) {
+                Some(interval) => daemon(&context, &options, interval).await,
+                None => {
+                    let report = sync(&context, &options, false).await;
+                    notify(&context, &report).await;
+                    if report.out_of_space {
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Commands::Redownload { guid } => {
+            let options = SyncOptions {
+                force: true,
+                guid: Some(guid),
+                ..options
+            };
+            let report = sync(&context, &options, false).await;
+            notify(&context, &report).await;
+        }
+        Commands::Migrate { new_dir } => {
+            let new_dir = match migrate(&context.config.download_dir, &new_dir) {
+                Ok(new_dir) => new_dir,
+                Err(err) => {
+                    println!("{}", format!("Failed to migrate library: {}", err).red());
+                    return;
+                }
+            };
+            println!("{}", format!("Moved library to {:?}", new_dir).green());
+            if let Err(err) = update_download_dir(&context.config_path, &new_dir) {
+                // The files have been moved already, so the config has to be fixed by hand.
+                println!(
+                    "{}",
+                    format!("Failed to update {:?}: {}", context.config_path, err).red()
+                );
+                println!(
+                    "Please set the new download directory in the config file:\n  download_dir = {}",
+                    toml::Value::String(new_dir.to_string_lossy().into_owned())
+                );
+                std::process::exit(1);
+            }
+        }
+        Commands::Clean { max_age, dry_run } => clean(&context, max_age, dry_run),
+        Commands::Tidy { aggressive } => tidy(&context, aggressive),
+        Commands::Dedupe { dry_run } => dedupe(&context, dry_run),
+        Commands::PruneState { remove_missing } => prune_state(&context, remove_missing),
+        Commands::Health { json } => health(&context, json),
+        Commands::Retag { dry_run } => retag(&context, dry_run).await,
+        Commands::Doctor | Commands::Config { .. } | Commands::Version { .. } => unreachable!(),
+        Commands::Discover { url } => discover(&context, &url).await,
+        Commands::Diff { opml, apply, prune } => diff(&context, &opml, apply, prune),
+        Commands::Add {
+            url,
+            title,
+            stdin,
+            fetch_titles,
+        } => {
+            let podcasts = if stdin {
+                let mut content = String::new();
+                if let Err(err) = std::io::stdin().read_to_string(&mut content) {
+                    eprintln!("{}", format!("Failed to read feed URLs: {}", err).red());
+                    std::process::exit(1);
+                }
+                parse_feed_list(&content)
+            } else {
+                // Unwrap is safe here, because clap ensures that a URL is present without --stdin.
+                vec![(url.unwrap(), title)]
+            };
+            add(&context, podcasts, fetch_titles).await;
+        }
+    }
+}
+
+/// Load the state, or print the error and exit with status 1.
+fn load_state_or_exit(context: &AppContext) -> State {
+    match context.load_state() {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("{}", format!("Failed to load state: {}", err).red());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Returns the canonical path of the download directory, or prints the error and exits with
+/// status 1.
+fn canonical_download_dir_or_exit(context: &AppContext) -> PathBuf {
+    match context.config.download_dir.canonicalize() {
+        Ok(download_dir) => download_dir,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!(
+                    "Failed to access download directory {:?}: {}",
+                    context.config.download_dir, err
+                )
+                .red()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the config as TOML.
+fn print_config(config: &Config, effective: bool) {
+    let config = if effective {
+        config.with_defaults().unwrap()
+    } else {
+        config.clone()
+    };
+    match toml::to_string_pretty(&config.redacted()) {
+        Ok(content) => print!("{}", content),
+        Err(err) => println!("{}", format!("Failed to serialize config: {}", err).red()),
+    }
+}
+
+/// Validate and add the given podcasts to the config file, skipping duplicates.
+///
+/// If `fetch_titles` is set, the titles of podcasts without a title are taken from their feeds.
+async fn add(context: &AppContext, podcasts: Vec<(String, Option<String>)>, fetch_titles: bool) {
+    let mut known_urls: HashSet<String> = context
+        .config
+        .podcast
+        .iter()
+        .map(|podcast| podcast.feed_url.clone())
+        .collect();
+    let mut new_podcasts = Vec::new();
+    let mut duplicate_count = 0;
+    let mut failed_count = 0;
+    for (feed_url, title) in podcasts {
+        if let Err(err) = validate_feed_url(&feed_url) {
+            println!(
+                "{}",
+                format!("Invalid feed URL {}: {}", feed_url, err).red()
+            );
+            failed_count += 1;
+            continue;
+        }
+
+        if !known_urls.insert(feed_url.clone()) {
+            println!("{}", format!("Already subscribed: {}", feed_url).yellow());
+            duplicate_count += 1;
+            continue;
+        }
+
+        println!("Adding {}", feed_url);
+        new_podcasts.push((feed_url, title));
+    }
+
+    if fetch_titles {
+        fetch_podcast_titles(context, &mut new_podcasts).await;
+    }
+    if !new_podcasts.is_empty() {
+        if let Err(err) = add_podcasts(&context.config_path, &new_podcasts) {
+            eprintln!(
+                "{}",
+                format!("Failed to update {:?}: {}", context.config_path, err).red()
+            );
+            std::process::exit(1);
+        }
+    }
+    println!(
+        "{}",
+        format!(
+            "Added {} feeds ({} duplicates skipped, {} invalid).",
+            new_podcasts.len(),
+            duplicate_count,
+            failed_count
+        )
+        .green()
+    );
+}
+
+/// Fill in the missing titles of `podcasts` with the titles from their feeds.
+///
+/// Feeds that cannot be fetched or have no title are left without a title.
+async fn fetch_podcast_titles(context: &AppContext, podcasts: &mut [(String, Option<String>)]) {
+    let untitled: Vec<_> = podcasts
+        .iter()
+        .filter(|(_, title)| title.is_none())
+        .map(|(feed_url, _)| PodcastConfig {
+            feed_url: feed_url.clone(),
+            ..PodcastConfig::default()
+        })
+        .collect();
+    if untitled.is_empty() {
         return;
     }
 
-    let (total_size, is_partial) =
-        files_to_download
-            .iter()
-            .fold((0, false), |(total_size, is_partial), file| {
-                let size = file.file_size.unwrap_or(0);
-                (total_size + size, is_partial || size == 0)
-            });
+    let mut titles = HashMap::new();
+    for (podcast, result) in fetch_channels(context, &untitled).await {
+        match result {
+            Ok(channel) if !channel.title.trim().is_empty() => {
+                titles.insert(&podcast.feed_url, channel.title.trim().to_owned());
+            }
+            Ok(_) => println!(
+                "{}",
+                format!("Feed has no title: {}", podcast.feed_url).yellow()
+            ),
+            Err(err) => println!(
+                "{}",
+                format!(
+                    "Failed to fetch {} (adding it without title): {}",
+                    podcast.feed_url, err
+                )
+                .yellow()
+            ),
+        }
+    }
+    for (feed_url, title) in podcasts.iter_mut() {
+        if title.is_none() {
+            *title = titles.remove(feed_url);
+        }
+    }
+}
+
+/// Compare the podcasts in the config with the feeds in the OPML file at `opml_path`.
+///
+/// If `apply` is set, the feeds that are missing in the config are added, and if `prune` is set
+/// too, the podcasts that are missing in the OPML file are removed.
+fn diff(context: &AppContext, opml_path: &Path, apply: bool, prune: bool) {
+    let feeds = match std::fs::read_to_string(opml_path)
+        .map_err(|err| err.to_string())
+        .and_then(|content| parse_opml(&content).map_err(|err| err.to_string()))
+    {
+        Ok(feeds) => feeds,
+        Err(err) => {
+            println!(
+                "{}",
+                format!("Failed to read {:?}: {}", opml_path, err).red()
+            );
+            return;
+        }
+    };
+
+    let mut config_urls: HashSet<String> = context
+        .config
+        .podcast
+        .iter()
+        .map(|podcast| normalize_feed_url(&podcast.feed_url))
+        .collect();
+    let opml_urls: HashSet<String> = feeds
+        .iter()
+        .map(|(feed_url, _)| normalize_feed_url(feed_url))
+        .collect();
+    let only_opml: Vec<_> = feeds
+        .into_iter()
+        .filter(|(feed_url, _)| validate_feed_url(feed_url).is_ok())
+        // Also skips duplicates within the OPML file.
+        .filter(|(feed_url, _)| config_urls.insert(normalize_feed_url(feed_url)))
+        .collect();
+    let only_config: Vec<_> = context
+        .config
+        .podcast
+        .iter()
+        .filter(|podcast| !opml_urls.contains(&normalize_feed_url(&podcast.feed_url)))
+        .map(|podcast| podcast.feed_url.as_str())
+        .collect();
 
-    let (human_size, human_size_suffix) = to_human_size(total_size);
-    if is_partial {
+    if only_opml.is_empty() && only_config.is_empty() {
         println!(
-            "Total Download Size: {}{} (size of some files is unknown)",
-            human_size, human_size_suffix
+            "{}",
+            "The config and the OPML file contain the same feeds.".green()
         );
+        return;
+    }
+    for (feed_url, title) in &only_opml {
+        match title {
+            Some(title) => println!("{}", format!("+ {} ({})", feed_url, title).green()),
+            None => println!("{}", format!("+ {}", feed_url).green()),
+        }
+    }
+    for feed_url in &only_config {
+        println!("{}", format!("- {}", feed_url).red());
+    }
+    println!(
+        "{} feeds only in the OPML file, {} feeds only in the config.",
+        only_opml.len(),
+        only_config.len()
+    );
+
+    if !apply {
+        return;
+    }
+    if !only_opml.is_empty() {
+        if let Err(err) = add_podcasts(&context.config_path, &only_opml) {
+            println!("{}", format!("Failed to add feeds: {}", err).red());
+            return;
+        }
+    }
+    let removed_count = if prune && !only_config.is_empty() {
+        if let Err(err) = remove_podcasts(&context.config_path, &only_config) {
+            println!("{}", format!("Failed to remove feeds: {}", err).red());
+            return;
+        }
+        only_config.len()
+    } else {
+        0
+    };
+    println!(
+        "{}",
+        format!(
+            "Added {} feeds, removed {} feeds.",
+            only_opml.len(),
+            removed_count
+        )
+        .green()
+    );
+}
+
+/// Discover the feeds linked on the website at `url` and add it if there is exactly one.
+async fn discover(context: &AppContext, url: &str) {
+    if let Err(err) = validate_feed_url(url) {
+        println!("{}", format!("Invalid URL {}: {}", url, err).red());
+        return;
+    }
+    // Unwrap is safe here, because the URL has been validated above.
+    let page_url = Url::parse(url).unwrap();
+
+    let feed_urls = match discover_feeds(&context.client, &page_url).await {
+        Ok(feed_urls) => feed_urls,
+        Err(err) => {
+            println!("{}", format!("Failed to fetch {}: {}", url, err).red());
+            return;
+        }
+    };
+
+    match feed_urls.as_slice() {
+        [] => println!(
+            "{}",
+            format!(
+                "No podcast feed is linked on {}. Try adding the feed URL directly.",
+                url
+            )
+            .yellow()
+        ),
+        [feed_url] => add(context, vec![(feed_url.to_string(), None)], false).await,
+        _ => {
+            println!("Multiple feeds found on {}:", url);
+            for feed_url in &feed_urls {
+                println!("  {}", feed_url);
+            }
+            println!("Use the `add` command to subscribe to one of them.");
+        }
+    }
+}
+
+/// Delete all episodes older than `max_age` (or only list them if `dry_run` is set).
+fn clean(context: &AppContext, max_age: Duration, dry_run: bool) {
+    let files = match find_expired_files(&context.config.download_dir, max_age) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("{}", format!("Failed to list episode files: {}", err).red());
+            std::process::exit(1);
+        }
+    };
+    if files.is_empty() {
+        println!("{}", "Nothing to do.".green());
+        return;
+    }
+
+    let mut state = load_state_or_exit(context);
+    let download_dir = canonical_download_dir_or_exit(context);
+    let mut state_changed = false;
+    let mut freed_size = 0;
+    for file in &files {
+        let (human_size, human_size_suffix) = to_human_size(file.size);
+        if dry_run {
+            println!(
+                "{}",
+                format!(
+                    "Would delete {:?} ({}{})",
+                    file.path, human_size, human_size_suffix
+                )
+                .yellow()
+            );
+        } else {
+            println!(
+                "Deleting {:?} ({}{})",
+                file.path, human_size, human_size_suffix
+            );
+            if let Err(err) = std::fs::remove_file(&file.path) {
+                println!(
+                    "{}",
+                    format!("Failed to delete {:?}: {}", file.path, err).red()
+                );
+                continue;
+            }
+            // Otherwise, the next sync would download the episode again.
+            if let Ok(relative_path) = file.path.strip_prefix(&download_dir) {
+                state_changed |= state.mark_file_removed(relative_path);
+            }
+        }
+        freed_size += file.size;
+    }
+
+    let (human_size, human_size_suffix) = to_human_size(freed_size);
+    if dry_run {
+        println!("Would free {}{}", human_size, human_size_suffix);
     } else {
-        println!("Total Download Size: {}{}", human_size, human_size_suffix);
+        println!(
+            "{}",
+            format!("Freed {}{}", human_size, human_size_suffix).green()
+        );
+        if state_changed {
+            if let Err(err) = state.save(&context.state_dir) {
+                println!("{}", format!("Failed to save state: {}", err).red());
+            }
+        }
+        remove_empty_dirs_and_report(context, false);
+    }
+}
+
+/// Delete files that have the same contents as another file in the library (or only list them if
+/// `dry_run` is set).
+fn dedupe(context: &AppContext, dry_run: bool) {
+    let download_dir = &context.config.download_dir;
+    let mut state = load_state_or_exit(context);
+    let groups = match find_duplicates(download_dir, &state) {
+        Ok(groups) => groups,
+        Err(err) => {
+            println!("{}", format!("Failed to find duplicates: {}", err).red());
+            return;
+        }
+    };
+    if groups.is_empty() {
+        println!("{}", "Nothing to do.".green());
+        return;
+    }
+
+    let download_dir = canonical_download_dir_or_exit(context);
+    let mut freed_size = 0;
+    let mut state_changed = false;
+    for group in &groups {
+        for file in &group.duplicates {
+            let (human_size, human_size_suffix) = to_human_size(file.size);
+            if dry_run {
+                println!(
+                    "{}",
+                    format!(
+                        "Would delete {:?} ({}{}, keeping {:?})",
+                        file.path, human_size, human_size_suffix, group.keep
+                    )
+                    .yellow()
+                );
+            } else {
+                println!(
+                    "Deleting {:?} ({}{}, keeping {:?})",
+                    file.path, human_size, human_size_suffix, group.keep
+                );
+                if let Err(err) = std::fs::remove_file(&file.path) {
+                    println!(
+                        "{}",
+                        format!("Failed to delete {:?}: {}", file.path, err).red()
+                    );
+                    continue;
+                }
+                // Episodes that were recorded with the deleted file now refer to the kept one.
+                if let (Ok(from), Ok(to)) = (
+                    file.path.strip_prefix(&download_dir),
+                    group.keep.strip_prefix(&download_dir),
+                ) {
+                    state_changed |= state.relink_file(from, to);
+                }
+            }
+            freed_size += file.size;
+        }
+    }
+
+    let (human_size, human_size_suffix) = to_human_size(freed_size);
+    if dry_run {
+        println!("Would free {}{}", human_size, human_size_suffix);
+        return;
+    }
+    println!(
+        "{}",
+        format!("Freed {}{}", human_size, human_size_suffix).green()
+    );
+    if state_changed {
+        if let Err(err) = state.save(&context.state_dir) {
+            println!("{}", format!("Failed to save state: {}", err).red());
+        }
+    }
+    remove_empty_dirs_and_report(context, false);
+}
+
+/// Remove the empty directories below the download directory and print them.
+///
+/// Returns the number of removed directories.
+fn remove_empty_dirs_and_report(context: &AppContext, aggressive: bool) -> usize {
+    match remove_empty_dirs(&context.config.download_dir, aggressive) {
+        Ok(removed) => {
+            for dir in &removed {
+                println!("Removed empty directory {:?}", dir);
+            }
+            removed.len()
+        }
+        Err(err) => {
+            println!(
+                "{}",
+                format!("Failed to remove empty directories: {}", err).red()
+            );
+            0
+        }
+    }
+}
+
+/// Remove empty podcast directories from the download directory.
+fn tidy(context: &AppContext, aggressive: bool) {
+    if remove_empty_dirs_and_report(context, aggressive) == 0 {
+        println!("{}", "Nothing to do.".green());
+    }
+}
+
+/// Report the health of all configured feeds based on their fetch history.
+fn health(context: &AppContext, json: bool) {
+    let state = load_state_or_exit(context);
+    let empty_history = VecDeque::new();
+    let now = unix_timestamp();
+    let feeds: Vec<_> = context
+        .config
+        .podcast
+        .iter()
+        .map(|podcast| {
+            let history = state
+                .feeds
+                .get(&podcast.feed_url)
+                .map_or(&empty_history, |feed| &feed.history);
+            feed_health(&podcast.feed_url, history, now)
+        })
+        .collect();
+    print!("{}", render_health(&feeds, json));
+}
+
+/// Remove outdated entries from the state and report how many were removed.
+fn prune_state(context: &AppContext, remove_missing: bool) {
+    let config = &context.config;
+    let mut state = load_state_or_exit(context);
+    let feed_count = state.prune_feeds(|feed_url| {
+        config
+            .podcast
+            .iter()
+            .any(|podcast| podcast.feed_url == feed_url)
+    });
+    println!("Removed {} unsubscribed feeds from the state.", feed_count);
+
+    let mut episode_count = 0;
+    if remove_missing {
+        episode_count = state.prune_missing_files(&config.download_dir);
+        println!(
+            "Removed {} episodes with missing files from the state.",
+            episode_count
+        );
+    }
+
+    if feed_count == 0 && episode_count == 0 {
+        println!("{}", "Nothing to do.".green());
+        return;
+    }
+
+    if let Err(err) = state.save(&context.state_dir) {
+        println!("{}", format!("Failed to save state: {}", err).red());
+    }
+}
+
+/// Write ID3 tags to the downloaded MP3 files of all podcasts, using the current feed metadata.
+async fn retag(context: &AppContext, dry_run: bool) {
+    let config = &context.config;
+    let state = load_state_or_exit(context);
+    let files = match list_files(&config.download_dir) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to list downloaded files: {}", err).red()
+            );
+            return;
+        }
+    };
+    let download_dir = canonical_download_dir_or_exit(context);
+
+    let mut progress = linya::Progress::new();
+    let mut tagged_count = 0;
+    let mut skipped_count = 0;
+    for (podcast, result) in fetch_channels(context, &config.podcast).await {
+        let channel = match result {
+            Ok(channel) => channel,
+            Err(err) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to fetch feed {}: {}", podcast.feed_url, err).red()
+                );
+                continue;
+            }
+        };
+
+        let podcast_title = podcast_dir_name(podcast, &channel.title);
+        let podcast_dir = download_dir.join(&podcast_title);
+        let mut items_by_guid = HashMap::new();
+        let mut items_by_file_name = HashMap::new();
+        for item in &channel.items {
+            if let Some(guid) = item_guid(item) {
+                items_by_guid.insert(guid, item);
+            }
+            if let Some(file_name) = item
+                .enclosure
+                .as_ref()
+                .and_then(|enc| Url::parse(&enc.url).ok())
+                .and_then(|url| {
+                    url.path_segments()
+                        .and_then(|mut segments| segments.next_back().map(str::to_owned))
+                })
+            {
+                items_by_file_name.insert(file_name, item);
+            }
+        }
+        let guids_by_path: HashMap<PathBuf, &str> = state
+            .feeds
+            .get(&podcast.feed_url)
+            .into_iter()
+            .flat_map(|feed| &feed.episodes)
+            .map(|(guid, episode)| (download_dir.join(&episode.file_path), guid.as_str()))
+            .collect();
+
+        let podcast_files: Vec<_> = files
+            .iter()
+            .filter(|file| file.path.starts_with(&podcast_dir) && is_taggable(&file.path))
+            .collect();
+        if podcast_files.is_empty() {
+            continue;
+        }
+
+        let bar = progress.bar(podcast_files.len(), &podcast_title);
+        for file in podcast_files {
+            let item = guids_by_path
+                .get(&file.path)
+                .and_then(|guid| items_by_guid.get(*guid))
+                .or_else(|| {
+                    file.path
+                        .file_name()
+                        .and_then(|file_name| file_name.to_str())
+                        .and_then(|file_name| items_by_file_name.get(file_name))
+                });
+            let item = match item {
+                Some(item) => item,
+                None => {
+                    skipped_count += 1;
+                    progress.inc_and_draw(&bar, 1);
+                    continue;
+                }
+            };
+
+            if dry_run {
+                eprintln!("Would tag {:?}", file.path);
+                tagged_count += 1;
+            } else {
+                let tags = EpisodeTags {
+                    podcast_title: &podcast_title,
+                    title: item.title.as_deref(),
+                    author: item
+                        .itunes_ext
+                        .as_ref()
+                        .and_then(|ext| ext.author.as_deref())
+                        .or_else(|| {
+                            channel
+                                .itunes_ext
+                                .as_ref()
+                                .and_then(|ext| ext.author.as_deref())
+                        }),
+                    pub_date: item.pub_date.as_deref().and_then(parse_date),
+                    description: item_show_notes(item),
+                };
+                match write_tags(&file.path, &tags) {
+                    Ok(()) => tagged_count += 1,
+                    Err(err) => eprintln!(
+                        "{}",
+                        format!("Failed to tag {:?}: {}", file.path, err).red()
+                    ),
+                }
+            }
+            progress.inc_and_draw(&bar, 1);
+        }
     }
+
     println!();
+    let summary = if dry_run {
+        format!("Would tag {} files.", tagged_count)
+    } else {
+        format!("Tagged {} files.", tagged_count)
+    };
+    println!("{}", summary.green());
+    if skipped_count > 0 {
+        println!(
+            "Skipped {} files that could not be matched to a feed item.",
+            skipped_count
+        );
+    }
+}
+
+/// Fetch the podcast feeds and list the episodes that would be downloaded.
+async fn status(context: &AppContext, format: StatusFormat, options: &SyncOptions) {
+    let mut state = load_state_or_exit(context);
+    let (files_to_download, _) = fetch_sync_info(context, &mut state, options).await;
+    print!(
+        "{}",
+        render_status(&files_to_download, format, options.include_downloaded)
+    );
+}
 
-    if args.command == Commands::Status {
-        println!("Files to download:");
-        for file in &files_to_download {
+/// List the episodes of the podcast with the feed URL from `options`.
+async fn episodes(context: &AppContext, options: &SyncOptions, json: bool) {
+    let feed_url = options.feed_url.as_deref().unwrap_or_default();
+    if !context
+        .config
+        .podcast
+        .iter()
+        .any(|podcast| podcast.feed_url == feed_url)
+    {
+        eprintln!(
+            "{}",
+            format!("Podcast not found in config: {}", feed_url).red()
+        );
+        return;
+    }
+
+    let mut state = load_state_or_exit(context);
+    let (episodes, _) = fetch_sync_info(context, &mut state, options).await;
+    print!("{}", render_episodes(&episodes, json));
+}
+
+/// Print the version and, if `check_updates` is set, whether a newer version is available.
+async fn print_version(check_updates: bool) {
+    println!("podcatcher-rs {}", CURRENT_VERSION);
+    if !check_updates {
+        return;
+    }
+
+    match fetch_latest_version(&Client::new()).await {
+        Ok(latest) if is_newer(&latest, CURRENT_VERSION) => {
             println!(
-                "  {} ({}, {})",
-                file.file_name(),
-                file.url,
-                file.human_file_size()
+                "{}",
+                format!("A newer version is available: {}", latest).yellow()
             );
         }
+        Ok(_) => println!("{}", "This is the latest version.".green()),
+        Err(err) => eprintln!("Failed to check for updates: {}", err),
+    }
+}
+
+/// Returns a random duration of at most `max_secs` seconds, derived from `seed`.
+fn jitter_duration(max_secs: u64, seed: u64) -> Duration {
+    let max_millis = max_secs.saturating_mul(1000);
+    Duration::from_millis(seed % max_millis.saturating_add(1))
+}
+
+/// Wait a random time of up to `startup_jitter_secs` before fetching the feeds.
+async fn startup_jitter(config: &Config, human_output: bool) {
+    let max_secs = config.startup_jitter_secs.unwrap_or(0);
+    if max_secs == 0 {
+        return;
+    }
+
+    // The hasher is randomly seeded for each process, which is good enough to spread the runs.
+    let seed = RandomState::new().build_hasher().finish();
+    let jitter = jitter_duration(max_secs, seed);
+    if human_output {
+        println!(
+            "Waiting {:.1}s before fetching feeds...",
+            jitter.as_secs_f64()
+        );
+    }
+    tokio::time::sleep(jitter).await;
+}
+
+/// Returns a receiver whose value changes to `true` once SIGINT or SIGTERM has been received.
+fn shutdown_receiver() -> std::io::Result<watch::Receiver<bool>> {
+    let (sender, receiver) = watch::channel(false);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = interrupt.recv() => (),
+                _ = terminate.recv() => (),
+            }
+            let _ = sender.send(true);
+        });
+    }
+    #[cfg(not(unix))]
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = sender.send(true);
+        }
+    });
+    Ok(receiver)
+}
+
+/// Returns the current local time for log messages.
+fn log_time() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Run `sync` repeatedly and wait `interval` between the runs.
+///
+/// A signal received during a run does not interrupt it, the daemon stops once it has finished.
+async fn daemon(context: &AppContext, options: &SyncOptions, interval: Duration) {
+    if interval.is_zero() {
+        eprintln!("{}", "Interval must be greater than zero.".red());
         return;
     }
 
-    println!("Fetching audio files...");
-    let progress = std::sync::Arc::new(Mutex::new(linya::Progress::new()));
+    let mut shutdown = match shutdown_receiver() {
+        Ok(shutdown) => shutdown,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to install signal handlers: {}", err).red()
+            );
+            return;
+        }
+    };
+
+    loop {
+        println!("[{}] Starting sync.", log_time());
+        let start = Instant::now();
+        let report = sync(context, options, false).await;
+        notify(context, &report).await;
+        println!(
+            "[{}] Finished sync in {:.1}s.",
+            log_time(),
+            start.elapsed().as_secs_f64()
+        );
+
+        if *shutdown.borrow() {
+            break;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = shutdown.changed() => break,
+        }
+    }
+    println!("[{}] Received shutdown signal, exiting.", log_time());
+}
+
+/// Fetch the podcast feeds, determine the sizes of the new episodes and record them in the state
+/// as pending, without downloading them.
+async fn resolve_only(context: &AppContext, options: &SyncOptions) {
+    let config = &context.config;
+    let mut state = load_state_or_exit(context);
+    let (mut downloads, skipped) = fetch_sync_info(context, &mut state, options).await;
+
+    // The sizes in the feeds are often missing or wrong, so they are only used as fallback.
+    let host_limiter = HostLimiter::new(config.max_connections_per_host.unwrap_or(4));
+    futures::stream::iter(&mut downloads)
+        .for_each_concurrent(config.max_parallel_downloads.unwrap_or(5), |dl| {
+            let host_limiter = &host_limiter;
+            async move {
+                let _permit = host_limiter.acquire(&dl.url).await;
+                let client = context.client_for(dl.proxy.as_deref());
+                if let Some(size) =
+                    retrieve_content_length(client, &dl.url, Some(&dl.headers)).await
+                {
+                    dl.file_size = Some(size);
+                }
+            }
+        })
+        .await;
+
+    for dl in &downloads {
+        let episode = PendingEpisode {
+            url: dl.url.to_string(),
+            title: dl.title.clone(),
+            pub_date: dl.pub_date,
+            file_size: dl.file_size,
+        };
+        state.mark_pending(&dl.feed_url, &dl.guid, episode);
+    }
+
+    println!();
+    if downloads.is_empty() {
+        println!("{}", "Nothing to do.".green());
+    } else {
+        print!("{}", render_status(&downloads, StatusFormat::Table, false));
+    }
+    print_skipped(skipped);
+    if let Err(err) = state.save(&context.state_dir) {
+        eprintln!("{}", format!("Failed to save state: {}", err).red());
+    }
+}
+
+/// Print how many episodes were skipped, because they have already been downloaded.
+fn print_skipped(skipped: SkippedEpisodes) {
+    if skipped.count > 0 {
+        let (human_size, human_size_suffix) = to_human_size(skipped.size);
+        println!(
+            "Skipped {} episodes ({}{} already downloaded).",
+            skipped.count, human_size, human_size_suffix
+        );
+    }
+    if skipped.over_limit > 0 {
+        println!(
+            "Skipped {} episodes over the total episode limit.",
+            skipped.over_limit
+        );
+    }
+}
+
+/// Send the configured notifications if the sync in `report` downloaded episodes.
+///
+/// Failures are only logged, because the sync itself has already finished.
+async fn notify(context: &AppContext, report: &SyncReport) {
+    let notify_config = match &context.config.notify {
+        Some(notify_config) if !report.is_empty() => notify_config,
+        _ => return,
+    };
+    for err in send_notifications(&context.client, notify_config, report).await {
+        eprintln!("{}", format!("Failed to send notification: {}", err).red());
+    }
+}
+
+/// Fetch the podcast feeds and download new episodes, and write the report file (if requested).
+async fn sync(context: &AppContext, options: &SyncOptions, interactive: bool) -> SyncReport {
+    let report = sync_episodes(context, options, interactive).await;
+    if let Some(report_path) = &options.report_path {
+        if let Err(err) = report.save(report_path) {
+            eprintln!(
+                "{}",
+                format!("Failed to write report to {:?}: {}", report_path, err).red()
+            );
+        }
+    }
+    report
+}
+
+/// Fetch the podcast feeds and download new episodes.
+async fn sync_episodes(
+    context: &AppContext,
+    options: &SyncOptions,
+    interactive: bool,
+) -> SyncReport {
+    let config = &context.config;
+    // In JSON lines mode, stdout is reserved for the events.
+    let human_output = !options.json_lines;
+    if let Err(err) = ensure_writable(&config.download_dir) {
+        eprintln!(
+            "{}",
+            format!(
+                "Download directory is not writable: {:?} ({})",
+                config.download_dir, err
+            )
+            .red()
+        );
+        return SyncReport::default();
+    }
+
+    if options.force_redownload && human_output {
+        println!(
+            "{}",
+            "Warning: Existing episode files will be downloaded again and overwritten!".yellow()
+        );
+    }
+
+    let mut state = match context.load_state() {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("{}", format!("Failed to load state: {}", err).red());
+            return SyncReport::default();
+        }
+    };
+    let started = unix_timestamp();
+
+    let max_jobs = config.max_parallel_downloads.unwrap_or(5usize);
+    let saved_queue = match SavedQueue::load(&context.state_dir) {
+        Ok(saved_queue) => saved_queue,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to load download queue: {}", err).red()
+            );
+            None
+        }
+    };
+    let (mut files_to_download, skipped): (Vec<EpisodeDownload>, _) = if options.resume {
+        match saved_queue {
+            Some(saved_queue) => (
+                saved_queue.into_downloads(context, &state),
+                SkippedEpisodes::default(),
+            ),
+            None => {
+                println!("{}", "There is no interrupted sync to resume.".green());
+                return SyncReport::default();
+            }
+        }
+    } else {
+        if let Some(saved_queue) = saved_queue.filter(|_| human_output) {
+            println!(
+                "{}",
+                format!(
+                    "The {} planned downloads of an interrupted sync are replaced by this sync \
+                     (use `sync --resume` to continue them instead).",
+                    saved_queue.episodes.len()
+                )
+                .yellow()
+            );
+        }
+        let (downloads, skipped) = fetch_sync_info(context, &mut state, options).await;
+        // Pending episodes that are not downloaded anymore (e.g. because they have left the
+        // feed) are forgotten. The others are removed once they have been downloaded.
+        if options.guid.is_none() {
+            state.prune_pending(started, |feed_url, guid| {
+                downloads
+                    .iter()
+                    .any(|dl| dl.feed_url == feed_url && dl.guid == guid)
+            });
+        }
+        (downloads, skipped)
+    };
+
+    if human_output && !options.summary_only {
+        println!();
+    }
+    if interactive
+        && !files_to_download.is_empty()
+        && std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal()
+    {
+        files_to_download = select_downloads(files_to_download);
+        println!();
+    }
+
+    // The planned downloads replace a previously saved queue, and are removed again once all of
+    // them have been downloaded.
+    let queue_result = if files_to_download.is_empty() {
+        SavedQueue::remove(&context.state_dir)
+    } else {
+        SavedQueue::new(&files_to_download).save(&context.state_dir)
+    };
+    if let Err(err) = queue_result {
+        eprintln!(
+            "{}",
+            format!("Failed to save download queue: {}", err).red()
+        );
+    }
+
+    if files_to_download.is_empty() {
+        if let Some(guid) = &options.guid {
+            println!(
+                "{}",
+                format!(
+                    "No episode with GUID {:?} found in the configured feeds.",
+                    guid
+                )
+                .red()
+            );
+        } else if human_output {
+            println!("{}", "Nothing to do.".green());
+            print_skipped(skipped);
+        }
+        record_last_run(&mut state, &config.podcast, started);
+        finish_sync(context, &mut state);
+        return SyncReport {
+            skipped: skipped.count + skipped.over_limit,
+            ..SyncReport::default()
+        };
+    }
+
+    let mut progress = None;
+    let mut total_bar = None;
+    if human_output && !options.summary_only {
+        println!("{}", render_total_size(&files_to_download));
+        println!();
+        println!("Fetching audio files...");
+
+        let mut multibar = linya::Progress::new();
+        // The combined progress is only shown if it can be computed accurately.
+        if let (total_size, false) = total_download_size(&files_to_download) {
+            if total_size > 0 {
+                total_bar = Some(multibar.bar(total_size, "Total"));
+            }
+        }
+        progress = Some(std::sync::Arc::new(Mutex::new(multibar)));
+    }
+    let total_bar = total_bar.as_ref();
+    let state = StateStore::new(state, &context.state_dir);
+    let verify_hashes = config.verify_hashes.unwrap_or(false);
+    let host_limiter = HostLimiter::new(config.max_connections_per_host.unwrap_or(4));
     let task_count = files_to_download.len();
-    futures::stream::iter(files_to_download.into_iter())
+    let failures = Mutex::new(Vec::new());
+    let downloaded = Mutex::new(Vec::new());
+    let controller = options
+        .concurrency_auto
+        .then(|| ConcurrencyController::new(max_jobs));
+    let controller = controller.as_ref();
+    // With `fail_fast`, the first failure aborts the stream, which cancels the running downloads.
+    // The same happens if the free space drops below `min_free_space`.
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let out_of_space = AtomicBool::new(false);
+    let downloads = futures::stream::iter(files_to_download)
         .enumerate()
-        .for_each_concurrent(max_jobs, move |(i, dl)| {
+        .for_each_concurrent(max_jobs, |(i, dl)| {
             let prog = progress.clone();
+            let state = &state;
+            let download_dir = &config.download_dir;
+            let host_limiter = &host_limiter;
+            let failures = &failures;
+            let downloaded = &downloaded;
+            let abort_handle = &abort_handle;
+            let out_of_space = &out_of_space;
             async move {
-                std::fs::create_dir_all(&dl.file_path.parent().unwrap()).unwrap();
-                let mut data = std::fs::File::create(&dl.file_path).unwrap();
-                download_file(
-                    &mut data,
-                    prog.clone(),
-                    &dl.url,
-                    dl.file_size,
-                    format!("({}/{}) {}", i + 1, &task_count, dl.file_name()).as_ref(),
+                let _job_permit = match controller {
+                    Some(controller) => Some(controller.acquire().await),
+                    None => None,
+                };
+                if !has_free_space(download_dir, config.min_free_space) {
+                    out_of_space.store(true, Ordering::Relaxed);
+                    abort_handle.abort();
+                    return;
+                }
+                if options.json_lines {
+                    Event::DownloadStarted {
+                        feed_url: &dl.feed_url,
+                        guid: &dl.guid,
+                        url: dl.url.as_str(),
+                        file_path: &dl.file_path,
+                        size: dl.file_size,
+                    }
+                    .emit();
+                }
+
+                let start = Instant::now();
+                let label = progress_label(i, task_count, &dl);
+                let progress = ProgressDisplay {
+                    multibar: prog,
+                    label: &label,
+                    total_bar,
+                };
+                let transferred = controller.map(ConcurrencyController::transferred);
+                let sha256 = match download_episode(
+                    context,
+                    host_limiter,
+                    &dl,
+                    verify_hashes,
+                    transferred,
+                    progress,
                 )
                 .await
-                .unwrap();
+                {
+                    Ok(sha256) => sha256,
+                    Err(err) => {
+                        if let Some(controller) = controller {
+                            controller.record_failure();
+                        }
+                        failures.lock().await.push(FailedEpisode {
+                            feed_url: dl.feed_url.clone(),
+                            guid: dl.guid.clone(),
+                            podcast_title: dl.podcast_title.clone(),
+                            title: dl.title.clone(),
+                            error: err.to_string(),
+                        });
+                        if options.json_lines {
+                            Event::DownloadFailed {
+                                feed_url: &dl.feed_url,
+                                guid: &dl.guid,
+                                file_path: &dl.file_path,
+                                error: err.to_string(),
+                            }
+                            .emit();
+                        } else {
+                            eprintln!(
+                                "{}",
+                                format!("Failed to download {}: {}", dl.file_name(), err).red()
+                            );
+                        }
+                        if options.fail_fast {
+                            abort_handle.abort();
+                        }
+                        return;
+                    }
+                };
+
+                for sidecar in &dl.sidecars {
+                    let path = dl.file_path.with_extension(&sidecar.extension);
+                    if let Err(err) = download_sidecar(
+                        context.client_for(dl.proxy.as_deref()),
+                        host_limiter,
+                        &sidecar.url,
+                        Some(&dl.headers),
+                        &path,
+                    )
+                    .await
+                    {
+                        eprintln!(
+                            "{}",
+                            format!(
+                                "Failed to download {} for {}: {}",
+                                sidecar.kind,
+                                dl.file_name(),
+                                err
+                            )
+                            .red()
+                        );
+                    }
+                }
+
+                if let Some(old_path) = dl.replaces.as_ref().filter(|path| **path != dl.file_path) {
+                    if let Err(err) = std::fs::remove_file(old_path) {
+                        eprintln!(
+                            "{}",
+                            format!("Failed to delete replaced file {:?}: {}", old_path, err).red()
+                        );
+                    }
+                }
+
+                let bytes = std::fs::metadata(&dl.file_path).map_or(0, |meta| meta.len());
+                if let Some(metadata) = &dl.metadata {
+                    let metadata = EpisodeMeta {
+                        size: Some(bytes),
+                        ..metadata.clone()
+                    };
+                    if let Err(err) = metadata.save(&dl.file_path) {
+                        eprintln!(
+                            "{}",
+                            format!("Failed to write metadata for {}: {}", dl.file_name(), err)
+                                .red()
+                        );
+                    }
+                }
+                let duration_secs = start.elapsed().as_secs_f64();
+                if options.json_lines {
+                    Event::DownloadCompleted {
+                        feed_url: &dl.feed_url,
+                        guid: &dl.guid,
+                        file_path: &dl.file_path,
+                        bytes,
+                        duration_secs,
+                    }
+                    .emit();
+                }
+
+                let relative_path = dl
+                    .file_path
+                    .strip_prefix(download_dir)
+                    .unwrap_or(&dl.file_path)
+                    .to_path_buf();
+                let episode = EpisodeState {
+                    file_path: relative_path,
+                    url: Some(dl.url.to_string()),
+                    title: dl.title.clone(),
+                    pub_date: dl.pub_date,
+                    duration: dl.duration,
+                    sha256,
+                    removed: false,
+                };
+                if let Err(err) = state.mark_downloaded(&dl.feed_url, &dl.guid, episode).await {
+                    eprintln!("{}", format!("Failed to save state: {}", err).red());
+                }
+                downloaded.lock().await.push(DownloadedEpisode {
+                    feed_url: dl.feed_url.clone(),
+                    guid: dl.guid.clone(),
+                    podcast_title: dl.podcast_title.clone(),
+                    title: dl.title.clone(),
+                    file_path: dl.file_path.clone(),
+                    bytes,
+                    duration_secs,
+                });
             }
-        })
+        });
+    let aborted = tokio::select! {
+        result = Abortable::new(downloads, abort_registration) => result.is_err(),
+        _ = adjust_concurrency(controller) => unreachable!(),
+        _ = wait_for_low_space(&config.download_dir, config.min_free_space) => {
+            out_of_space.store(true, Ordering::Relaxed);
+            true
+        }
+    };
+    let out_of_space = out_of_space.into_inner();
+
+    let failures = failures.into_inner();
+    let failed_count = failures.len();
+    let downloaded = downloaded.into_inner();
+    if human_output {
+        if !options.summary_only {
+            println!();
+        }
+        let downloaded_size: u64 = downloaded.iter().map(|episode| episode.bytes).sum();
+        let (human_size, human_size_suffix) = to_human_size(downloaded_size as usize);
+        let summary = format!(
+            "Downloaded {} episodes ({}{}).",
+            downloaded.len(),
+            human_size,
+            human_size_suffix
+        );
+        if out_of_space {
+            let (human_size, human_size_suffix) =
+                to_human_size(config.min_free_space.unwrap_or_default());
+            println!(
+                "{} {}",
+                summary.yellow(),
+                format!(
+                    "Stopped, because the free space in {:?} dropped below {}{}, {} downloads \
+                     were cancelled.",
+                    config.download_dir,
+                    human_size,
+                    human_size_suffix,
+                    task_count - downloaded.len() - failed_count
+                )
+                .red()
+            );
+        } else if aborted {
+            println!(
+                "{} {}",
+                summary.yellow(),
+                format!(
+                    "Aborted after the first failed download, {} downloads were cancelled.",
+                    task_count - downloaded.len() - failed_count
+                )
+                .red()
+            );
+        } else if failed_count > 0 {
+            println!(
+                "{} {}",
+                summary.yellow(),
+                format!("{} downloads failed.", failed_count).red()
+            );
+        } else {
+            println!("{}", summary.green());
+        }
+        print_skipped(skipped);
+        if let Some(controller) = controller.filter(|_| options.verbose) {
+            println!("Ended with {} parallel downloads.", controller.limit());
+        }
+    }
+    // The queue is kept if downloads failed, so that they can be retried with `--resume`.
+    if failed_count == 0 && !aborted {
+        if let Err(err) = SavedQueue::remove(&context.state_dir) {
+            eprintln!(
+                "{}",
+                format!("Failed to remove download queue: {}", err).red()
+            );
+        }
+    }
+    let mut state = state.lock().await;
+    if failed_count == 0 && !aborted {
+        record_last_run(&mut state, &config.podcast, started);
+    }
+    finish_sync(context, &mut state);
+    SyncReport {
+        downloaded,
+        failed: failed_count,
+        failures,
+        skipped: skipped.count + skipped.over_limit,
+        out_of_space,
+    }
+}
+
+/// Time between two checks of the free space during the downloads.
+const FREE_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns `false` if the free space in `dir` is below `min_free_space`.
+///
+/// If the free space cannot be determined, it is assumed to be sufficient.
+fn has_free_space(dir: &Path, min_free_space: Option<usize>) -> bool {
+    match min_free_space {
+        Some(min_free_space) => {
+            fs2::available_space(dir).map_or(true, |space| space >= min_free_space as u64)
+        }
+        None => true,
+    }
+}
+
+/// Wait until the free space in `dir` drops below `min_free_space` (forever if it is not set).
+async fn wait_for_low_space(dir: &Path, min_free_space: Option<usize>) {
+    if min_free_space.is_none() {
+        return futures::future::pending().await;
+    }
+    while has_free_space(dir, min_free_space) {
+        tokio::time::sleep(FREE_SPACE_CHECK_INTERVAL).await;
+    }
+}
+
+/// Record the start of the sync as `last_run` in the `state` if all `podcasts` have been fetched
+/// successfully since then.
+///
+/// Syncs that skipped feeds (e.g. with `--resume`, for a single episode, or because their update
+/// interval has not elapsed or they are backed off) are not recorded, because they may have
+/// missed new episodes.
+fn record_last_run(state: &mut State, podcasts: &[PodcastConfig], started: u64) {
+    if podcasts
+        .iter()
+        .all(|podcast| state.fetched_since(&podcast.feed_url, started))
+    {
+        state.last_run = Some(started);
+    }
+}
+
+/// Adjust the limit of the `controller` (if any) periodically, until the future is dropped.
+async fn adjust_concurrency(controller: Option<&ConcurrencyController>) {
+    match controller {
+        Some(controller) => loop {
+            tokio::time::sleep(CONCURRENCY_SAMPLE_INTERVAL).await;
+            controller.adjust();
+        },
+        None => futures::future::pending().await,
+    }
+}
+
+/// Download a single episode to its `file_path` and return its hash (if `verify_hashes` is set).
+///
+/// If the download fails, it is retried from the mirrors of the podcast (if any), unless the
+/// server rejected the credentials of the request.
+async fn download_episode(
+    context: &AppContext,
+    host_limiter: &HostLimiter,
+    dl: &EpisodeDownload,
+    verify_hashes: bool,
+    transferred: Option<&AtomicUsize>,
+    progress: ProgressDisplay<'_>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(parent) = dl.file_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut urls = std::iter::once(&dl.url).chain(&dl.mirror_urls).peekable();
+    loop {
+        // Unwrap is safe here, because the loop returns after the last URL.
+        let url = urls.next().unwrap();
+        // A new partial file is created for each attempt, so that no data is left over from a
+        // failed one.
+        let mut data = PartialFile::create(&dl.file_path, context.config.temp_dir.as_deref())?;
+        let result = download_file(
+            context.client_for(dl.proxy.as_deref()),
+            data.file(),
+            host_limiter,
+            &FileRequest {
+                url,
+                headers: Some(&dl.headers),
+                file_size: dl.file_size,
+                throttle: dl.throttle.as_deref(),
+                max_size: None,
+                transferred,
+            },
+            verify_hashes,
+            progress.clone(),
+        )
+        .await
+        .and_then(|sha256| match &dl.integrity {
+            Some(integrity) if !verify_integrity(data.file(), integrity)? => {
+                Err("File does not match the hash from the feed".into())
+            }
+            _ => Ok(sha256),
+        });
+        match result {
+            Ok(sha256) => {
+                data.finish()?;
+                return Ok(sha256);
+            }
+            Err(err) => match urls.peek() {
+                Some(mirror_url) if !is_credentials_rejected(err.as_ref(), url, &dl.headers) => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Failed to download {} from {}: {} (trying {})",
+                            dl.file_name(),
+                            url.host_str().unwrap_or_default(),
+                            err,
+                            mirror_url.host_str().unwrap_or_default()
+                        )
+                        .yellow()
+                    )
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+/// List the downloads and let the user choose which of them should be downloaded.
+fn select_downloads(downloads: Vec<EpisodeDownload>) -> Vec<EpisodeDownload> {
+    println!("New episodes:");
+    for (i, download) in downloads.iter().enumerate() {
+        println!(
+            "  {:>3}) [{}] {} ({})",
+            i + 1,
+            download.podcast_title,
+            download.file_name(),
+            download.human_file_size()
+        );
+    }
+
+    let selection = loop {
+        print!("Episodes to download (e.g. `1 3-5`, `all` or `none`) [all]: ");
+        // A failed flush only affects the prompt, so it can be ignored.
+        let _ = std::io::stdout().flush();
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+            // Stdin was closed, so there is nothing to select.
+            break Vec::new();
+        }
+
+        match parse_selection(&input, downloads.len()) {
+            Ok(selection) => break selection,
+            Err(err) => println!("{}", err.red()),
+        }
+    };
+
+    downloads
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selection.binary_search(i).is_ok())
+        .map(|(_, download)| download)
+        .collect()
+}
+
+/// Returns the label for the progress bar of the `i`-th download.
+///
+/// The label is truncated so that it fits into the space that `linya` reserves for it.
+fn progress_label(i: usize, task_count: usize, dl: &EpisodeDownload) -> String {
+    let label = format!(
+        "({}/{}) [{}] {}",
+        i + 1,
+        task_count,
+        dl.podcast_title,
+        dl.file_name()
+    );
+    let terminal_width = terminal_size::terminal_size().map_or(100, |(width, _)| width.0 as usize);
+    // This mirrors the layout computation of `linya::Progress::bar`.
+    let max_width = terminal_width
+        .saturating_sub((terminal_width / 2).saturating_sub(7))
+        .saturating_sub(13);
+    if label.chars().count() <= max_width {
+        return label;
+    }
+
+    let mut label: String = label.chars().take(max_width.saturating_sub(1)).collect();
+    label.push('…');
+    label
+}
+
+/// Delete the oldest episodes until the library is not larger than `max_size`.
+///
+/// Deleted episodes are marked in the `state`, so that they are not downloaded again. Messages
+/// are printed to stderr, so that they don't interfere with `--json-lines` output.
+fn enforce_library_size(context: &AppContext, state: &mut State, max_size: usize) {
+    let config = &context.config;
+    let keep_at_least = |feed_url: &str| {
+        config
+            .podcast
+            .iter()
+            .find(|podcast| podcast.feed_url == feed_url)
+            .and_then(|podcast| podcast.keep_at_least)
+            .or(config.keep_at_least)
+            .unwrap_or(0)
+    };
+    let evictions = match find_evictions(&config.download_dir, state, max_size, keep_at_least) {
+        Ok(evictions) => evictions,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Failed to check the library size: {}", err).red()
+            );
+            return;
+        }
+    };
+
+    let mut freed_size = 0;
+    for eviction in &evictions {
+        let (human_size, human_size_suffix) = to_human_size(eviction.file.size);
+        eprintln!(
+            "{}",
+            format!(
+                "Evicting {:?} ({}{})",
+                eviction.file.path, human_size, human_size_suffix
+            )
+            .yellow()
+        );
+        if let Err(err) = std::fs::remove_file(&eviction.file.path) {
+            eprintln!(
+                "{}",
+                format!("Failed to delete {:?}: {}", eviction.file.path, err).red()
+            );
+            continue;
+        }
+        state.mark_removed(&eviction.feed_url, &eviction.guid);
+        freed_size += eviction.file.size;
+        // The additional files (e.g. chapters) are useless without the episode.
+        for sidecar in existing_sidecars(&eviction.file.path) {
+            let size = std::fs::metadata(&sidecar).map_or(0, |meta| meta.len() as usize);
+            match std::fs::remove_file(&sidecar) {
+                Ok(()) => freed_size += size,
+                Err(err) => eprintln!(
+                    "{}",
+                    format!("Failed to delete {:?}: {}", sidecar, err).red()
+                ),
+            }
+        }
+    }
+
+    if !evictions.is_empty() {
+        let (human_size, human_size_suffix) = to_human_size(freed_size);
+        eprintln!(
+            "Evicted {} episodes to enforce the library size limit ({}{} freed).",
+            evictions.len(),
+            human_size,
+            human_size_suffix
+        );
+    }
+}
+
+/// Write the playlists and save the state after a sync.
+fn finish_sync(context: &AppContext, state: &mut State) {
+    let config = &context.config;
+    if let Some(max_size) = config.max_library_size {
+        enforce_library_size(context, state, max_size);
+    }
+
+    let generate_playlist = config.generate_playlist.unwrap_or(false);
+    for podcast in &config.podcast {
+        if !podcast.generate_playlist.unwrap_or(generate_playlist) {
+            continue;
+        }
+
+        if let Some(feed) = state.feeds.get(&podcast.feed_url) {
+            if let Err(err) = write_playlists(&config.download_dir, feed) {
+                eprintln!(
+                    "{}",
+                    format!("Failed to write playlist for {}: {}", podcast.feed_url, err).red()
+                );
+            }
+        }
+    }
+
+    if let Err(err) = state.save(&context.state_dir) {
+        eprintln!("{}", format!("Failed to save state: {}", err).red());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension::PODCAST_NAMESPACE;
+    use crate::state::FetchResult;
+    use crate::test_server::{test_dir, TestResponse, TestServer};
+
+    fn podcast(feed_url: &str) -> PodcastConfig {
+        PodcastConfig {
+            feed_url: feed_url.to_owned(),
+            ..PodcastConfig::default()
+        }
+    }
+
+    fn record_fetch(state: &mut State, feed_url: &str, timestamp: u64, success: bool) {
+        state.record_fetch_result(
+            feed_url,
+            FetchResult {
+                timestamp,
+                success,
+                status: None,
+                error: None,
+                items: None,
+                latest_pub_date: None,
+            },
+        );
+    }
+
+    #[test]
+    fn record_last_run_after_fetching_all_feeds() {
+        let podcasts = [
+            podcast("https://a.example/feed"),
+            podcast("https://b.example/feed"),
+        ];
+        let mut state = State::default();
+        record_fetch(&mut state, "https://a.example/feed", 100, true);
+        record_fetch(&mut state, "https://b.example/feed", 101, true);
+        record_last_run(&mut state, &podcasts, 100);
+        assert_eq!(state.last_run, Some(100));
+    }
+
+    #[test]
+    fn record_last_run_skips_failed_feeds() {
+        let podcasts = [
+            podcast("https://a.example/feed"),
+            podcast("https://b.example/feed"),
+        ];
+        let mut state = State::default();
+        record_fetch(&mut state, "https://a.example/feed", 100, true);
+        record_fetch(&mut state, "https://b.example/feed", 100, false);
+        record_last_run(&mut state, &podcasts, 100);
+        assert_eq!(state.last_run, None);
+    }
+
+    #[test]
+    fn record_last_run_skips_feeds_that_were_not_fetched() {
+        // The second feed has been skipped (e.g. because of its update interval), so its last
+        // fetch is older than the sync.
+        let podcasts = [
+            podcast("https://a.example/feed"),
+            podcast("https://b.example/feed"),
+        ];
+        let mut state = State {
+            last_run: Some(50),
+            ..State::default()
+        };
+        record_fetch(&mut state, "https://a.example/feed", 100, true);
+        record_fetch(&mut state, "https://b.example/feed", 60, true);
+        record_last_run(&mut state, &podcasts, 100);
+        assert_eq!(state.last_run, Some(50));
+    }
+
+    /// Plan the download of the only episode of the feed on `server`, whose enclosure is
+    /// `/episode.mp3`, with the additional `item_elements` in the feed and `podcast_options` in
+    /// the configuration of the podcast.
+    async fn plan_episode(
+        name: &str,
+        server: &TestServer,
+        item_elements: &str,
+        podcast_options: &str,
+    ) -> (AppContext, EpisodeDownload) {
+        server.serve(
+            "/feed.xml",
+            TestResponse::ok(format!(
+                "<?xml version=\"1.0\"?><rss version=\"2.0\" xmlns:podcast=\"{}\">\
+                 <channel><title>Fixture</title><item><guid>episode</guid><title>Episode</title>\
+                 <enclosure url=\"{}\" length=\"7\" type=\"audio/mpeg\"/>{}</item>\
+                 </channel></rss>",
+                PODCAST_NAMESPACE,
+                server.url("/episode.mp3"),
+                item_elements,
+            )),
+        );
+        let dir = test_dir(name);
+        let config = Config::from_toml_str(&format!(
+            "download_dir = {:?}\nstate_dir = {:?}\n\n[[podcast]]\nfeed_url = {:?}\n{}",
+            dir.join("downloads"),
+            dir.join("state"),
+            server.url("/feed.xml"),
+            podcast_options,
+        ))
+        .unwrap();
+        let context = AppContext::new(
+            Client::new(),
+            HashMap::new(),
+            dir.join("config.toml"),
+            config,
+        )
+        .unwrap();
+        let options = SyncOptions {
+            summary_only: true,
+            ..SyncOptions::default()
+        };
+        let (mut downloads, _) = fetch_sync_info(&context, &mut State::default(), &options).await;
+        assert_eq!(downloads.len(), 1);
+        (context, downloads.remove(0))
+    }
+
+    fn no_progress() -> ProgressDisplay<'static> {
+        ProgressDisplay {
+            multibar: None,
+            label: "",
+            total_bar: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn download_episode_skips_mirrors_if_credentials_are_rejected() {
+        let server = TestServer::start().await;
+        let mirror = TestServer::start().await;
+        server.serve("/episode.mp3", TestResponse::status(401));
+        mirror.serve("/episode.mp3", TestResponse::ok("episode"));
+        let (context, dl) = plan_episode(
+            "mirror-credentials-rejected",
+            &server,
+            "",
+            &format!(
+                "mirrors = [{:?}]\nheaders = {{ Authorization = \"Bearer secret\" }}\n",
+                mirror.url("")
+            ),
+        )
+        .await;
+        assert_eq!(dl.mirror_urls.len(), 1);
+        let result = download_episode(
+            &context,
+            &HostLimiter::new(1),
+            &dl,
+            false,
+            None,
+            no_progress(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(mirror.requests().is_empty());
+        assert!(!dl.file_path.exists());
+    }
+
+    #[tokio::test]
+    async fn download_episode_falls_back_to_mirror() {
+        let server = TestServer::start().await;
+        let mirror = TestServer::start().await;
+        mirror.serve("/episode.mp3", TestResponse::ok("episode"));
+        let (context, dl) = plan_episode(
+            "mirror-fallback",
+            &server,
+            "",
+            &format!("mirrors = [{:?}]\n", mirror.url("")),
+        )
         .await;
+        download_episode(
+            &context,
+            &HostLimiter::new(1),
+            &dl,
+            false,
+            None,
+            no_progress(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&dl.file_path).unwrap(), "episode");
+        assert!(server.requests().contains(&"GET /episode.mp3".to_owned()));
+    }
+
+    /// Download `/episode.mp3` from `server`, with the given hash in the feed.
+    async fn download_with_integrity(name: &str, value: &str) -> Result<(), String> {
+        let server = TestServer::start().await;
+        server.serve("/episode.mp3", TestResponse::ok("episode"));
+        let (context, dl) = plan_episode(
+            name,
+            &server,
+            &format!("<podcast:integrity type=\"sri\" value=\"{}\"/>", value),
+            "",
+        )
+        .await;
+        assert!(dl.integrity.is_some());
+        let result = download_episode(
+            &context,
+            &HostLimiter::new(1),
+            &dl,
+            false,
+            None,
+            no_progress(),
+        )
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string());
+        // The file is only kept if it matches the hash.
+        assert_eq!(dl.file_path.exists(), result.is_ok());
+        result
+    }
+
+    #[tokio::test]
+    async fn download_episode_verifies_integrity() {
+        assert_eq!(
+            download_with_integrity(
+                "integrity-match",
+                "sha256-fkFOasQax6BljOKAdUivj7SLFF+bdKp3ybUmxWmAS7w="
+            )
+            .await,
+            Ok(())
+        );
+        assert_eq!(
+            download_with_integrity(
+                "integrity-mismatch",
+                "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            )
+            .await,
+            Err("File does not match the hash from the feed".to_owned())
+        );
+    }
+
+    #[test]
+    fn jitter_duration_is_derived_from_seed() {
+        assert_eq!(jitter_duration(10, 0), Duration::ZERO);
+        assert_eq!(jitter_duration(10, 2_344), Duration::from_millis(2_344));
+        assert_eq!(jitter_duration(10, 12_345), Duration::from_millis(2_344));
+        assert_eq!(jitter_duration(10, 10_000), Duration::from_secs(10));
+        assert_eq!(jitter_duration(0, 12_345), Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_duration_stays_within_maximum() {
+        for seed in (0..64).map(|i: u64| i.wrapping_mul(0x9e37_79b9_7f4a_7c15)) {
+            assert!(jitter_duration(30, seed) <= Duration::from_secs(30));
+        }
+        // Huge maximums don't overflow.
+        assert_eq!(jitter_duration(u64::MAX, u64::MAX), Duration::ZERO);
+        assert_eq!(
+            jitter_duration(u64::MAX, 5_000),
+            Duration::from_millis(5_000)
+        );
+    }
 }
@@ -0,0 +1,123 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Resources that are shared between the commands.
+
+use crate::config::{Config, IpVersion};
+use crate::netrc::Netrc;
+use crate::state::State;
+use reqwest::{Client, ClientBuilder, Proxy};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Build the HTTP client that is used for all requests.
+///
+/// All client settings (e.g. timeouts or proxies) should be configured here.
+pub fn build_client(config: &Config) -> reqwest::Result<Client> {
+    client_builder(config).build()
+}
+
+/// Build a separate HTTP client for each proxy that is configured for a podcast, keyed by the
+/// proxy URL.
+pub fn build_proxy_clients(config: &Config) -> reqwest::Result<HashMap<String, Client>> {
+    let mut clients = HashMap::new();
+    for proxy in config
+        .podcast
+        .iter()
+        .filter_map(|podcast| podcast.proxy.as_ref())
+    {
+        if !clients.contains_key(proxy) {
+            let client = client_builder(config).proxy(Proxy::all(proxy)?).build()?;
+            clients.insert(proxy.clone(), client);
+        }
+    }
+    Ok(clients)
+}
+
+/// Returns a client builder with the connection settings from the config.
+fn client_builder(config: &Config) -> ClientBuilder {
+    // The headers have been validated when loading the config. Headers set on a request (e.g.
+    // the custom headers of a podcast) take precedence over these.
+    let mut builder = Client::builder().default_headers(config.header_map().unwrap_or_default());
+    if config.http2_prior_knowledge.unwrap_or(false) {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(timeout) = config.pool_idle_timeout_secs {
+        builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+    }
+    // Binding to the unspecified address of one family restricts the connections to that family.
+    match config.ip_version.unwrap_or_default() {
+        IpVersion::Auto => (),
+        IpVersion::V4 => builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpVersion::V6 => builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    }
+    builder
+}
+
+/// Everything a command needs to run, constructed once in `cli::main`.
+#[derive(Debug)]
+pub struct AppContext {
+    /// HTTP client used for all requests of podcasts without a proxy.
+    pub client: Client,
+    /// HTTP clients for the podcasts with a proxy, keyed by the proxy URL.
+    pub proxy_clients: HashMap<String, Client>,
+    /// Location of the config file.
+    pub config_path: PathBuf,
+    /// The loaded config.
+    pub config: Config,
+    /// Directory where the state is stored.
+    pub state_dir: PathBuf,
+    /// Credentials from the user's `.netrc` file (only loaded if `use_netrc` is set).
+    pub netrc: Option<Netrc>,
+}
+
+impl AppContext {
+    /// Create a new context from the config at `config_path`.
+    pub fn new(
+        client: Client,
+        proxy_clients: HashMap<String, Client>,
+        config_path: PathBuf,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        let state_dir = config.state_dir()?;
+        let netrc = match config.use_netrc {
+            Some(true) => Some(Netrc::load().map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("Failed to read the .netrc file: {}", err),
+                )
+            })?),
+            _ => None,
+        };
+        Ok(Self {
+            client,
+            proxy_clients,
+            config_path,
+            config,
+            state_dir,
+            netrc,
+        })
+    }
+
+    /// Returns the client for requests that should go through `proxy` (if any).
+    pub fn client_for(&self, proxy: Option<&str>) -> &Client {
+        proxy
+            .and_then(|proxy| self.proxy_clients.get(proxy))
+            .unwrap_or(&self.client)
+    }
+
+    /// Load the state from the state directory.
+    pub fn load_state(&self) -> std::io::Result<State> {
+        State::load(&self.state_dir)
+    }
+}
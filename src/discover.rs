@@ -0,0 +1,52 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for discovering podcast feeds on websites.
+
+use crate::trace;
+use reqwest::{Client, Url};
+use scraper::{Html, Selector};
+
+/// Returns the URLs of all RSS feeds that are linked in the `<head>` of an HTML page, i.e. via
+/// `<link rel="alternate" type="application/rss+xml" href="...">`.
+///
+/// Relative URLs are resolved against `page_url`.
+pub fn find_feed_links(html: &str, page_url: &Url) -> Vec<Url> {
+    let document = Html::parse_document(html);
+    // Unwrap is safe here, because the selector is hard-coded.
+    let selector =
+        Selector::parse(r#"link[rel~="alternate"][type="application/rss+xml"]"#).unwrap();
+
+    let mut feed_urls: Vec<Url> = Vec::new();
+    for element in document.select(&selector) {
+        let url = element
+            .value()
+            .attr("href")
+            .and_then(|href| page_url.join(href.trim()).ok());
+        if let Some(url) = url {
+            if !feed_urls.contains(&url) {
+                feed_urls.push(url);
+            }
+        }
+    }
+    feed_urls
+}
+
+/// Fetch the web page at `page_url` and return the URLs of the feeds linked on it.
+pub async fn discover_feeds(
+    client: &Client,
+    page_url: &Url,
+) -> Result<Vec<Url>, Box<dyn std::error::Error>> {
+    let response = trace::send(client.get(page_url.as_str()))
+        .await?
+        .error_for_status()?;
+    // Use the URL after redirects, so that relative links are resolved correctly.
+    let page_url = response.url().clone();
+    let html = response.text().await?;
+    Ok(find_feed_links(&html, &page_url))
+}
@@ -0,0 +1,201 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Diagnostics for troubleshooting the environment.
+
+use crate::config::Config;
+use crate::context::{build_client, build_proxy_clients};
+use crate::download::{check_url, to_human_size};
+use crate::library::ensure_writable;
+use colored::Colorize;
+use reqwest::Url;
+use std::path::PathBuf;
+
+/// URL that is used to check if the network is reachable at all.
+const CONNECTIVITY_CHECK_URL: &str = "https://example.com/";
+
+/// Free disk space below which a warning is shown (in bytes).
+const LOW_DISK_SPACE: u64 = 1_000_000_000;
+
+/// Result of a single diagnostic check.
+#[derive(Debug, PartialEq)]
+enum Status {
+    /// The check passed.
+    Pass,
+    /// The check failed, but syncing might still work.
+    Warn,
+    /// The check failed and syncing will not work.
+    Fail,
+}
+
+/// Print the result of a check, followed by an optional hint on how to fix the problem.
+fn report(status: &Status, message: &str, hint: Option<&str>) {
+    let label = match status {
+        Status::Pass => " OK ".green(),
+        Status::Warn => "WARN".yellow(),
+        Status::Fail => "FAIL".red(),
+    };
+    println!("[{}] {}", label, message);
+    if let (Some(hint), false) = (hint, status == &Status::Pass) {
+        println!("       Hint: {}", hint);
+    }
+}
+
+/// Check the environment and print a checklist of the results.
+///
+/// Returns `false` if any critical check failed.
+pub async fn run(config_path: std::io::Result<PathBuf>) -> bool {
+    let config_path = match config_path {
+        Ok(path) => path,
+        Err(err) => {
+            report(
+                &Status::Fail,
+                &format!("Unable to determine config path: {}", err),
+                Some("Pass the config file location with `--config`."),
+            );
+            return false;
+        }
+    };
+
+    if !config_path.is_file() {
+        report(
+            &Status::Fail,
+            &format!("Config file not found: {:?}", config_path),
+            Some("Create the config file or pass its location with `--config`."),
+        );
+        return false;
+    }
+
+    let config = match Config::from_path(&config_path) {
+        Ok(config) => {
+            report(
+                &Status::Pass,
+                &format!("Config file is valid: {:?}", config_path),
+                None,
+            );
+            config
+        }
+        Err(err) => {
+            report(
+                &Status::Fail,
+                &format!("Config file is invalid: {}", err),
+                Some("Fix the syntax error or missing field in the config file."),
+            );
+            return false;
+        }
+    };
+
+    let clients =
+        build_client(&config).and_then(|client| Ok((client, build_proxy_clients(&config)?)));
+    let (client, proxy_clients) = match clients {
+        Ok(clients) => clients,
+        Err(err) => {
+            report(
+                &Status::Fail,
+                &format!("Unable to create HTTP client: {}", err),
+                Some("Check the connection settings in the config file."),
+            );
+            return false;
+        }
+    };
+
+    let mut success = true;
+    let download_dir = &config.download_dir;
+    if !download_dir.is_dir() {
+        report(
+            &Status::Fail,
+            &format!("Download directory does not exist: {:?}", download_dir),
+            Some("Create the directory or change `download_dir` in the config file."),
+        );
+        success = false;
+    } else if let Err(err) = ensure_writable(download_dir) {
+        report(
+            &Status::Fail,
+            &format!(
+                "Download directory is not writable: {:?} ({})",
+                download_dir, err
+            ),
+            Some(
+                "Check the permissions of the directory and make sure it is not mounted read-only.",
+            ),
+        );
+        success = false;
+    } else {
+        report(
+            &Status::Pass,
+            &format!("Download directory is writable: {:?}", download_dir),
+            None,
+        );
+
+        match fs2::available_space(download_dir) {
+            Ok(space) => {
+                let (human_size, human_size_suffix) = to_human_size(space as usize);
+                let status = if space < LOW_DISK_SPACE {
+                    Status::Warn
+                } else {
+                    Status::Pass
+                };
+                report(
+                    &status,
+                    &format!("Free disk space: {}{}", human_size, human_size_suffix),
+                    Some("Free up some disk space, e.g. using the `clean` command."),
+                );
+            }
+            Err(err) => report(
+                &Status::Warn,
+                &format!("Unable to determine free disk space: {}", err),
+                None,
+            ),
+        }
+    }
+
+    // Unwrap is safe here, because the URL is hard-coded.
+    let connectivity_check_url = Url::parse(CONNECTIVITY_CHECK_URL).unwrap();
+    match check_url(&client, &connectivity_check_url, None).await {
+        Ok(_) => report(&Status::Pass, "Network is reachable", None),
+        Err(err) => {
+            report(
+                &Status::Fail,
+                &format!("Network is not reachable: {}", err),
+                Some("Check your internet connection and proxy settings."),
+            );
+            success = false;
+        }
+    }
+
+    for podcast in &config.podcast {
+        // The headers have been validated when loading the config.
+        let headers = podcast.header_map().unwrap_or_default();
+        let client = podcast
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy_clients.get(proxy))
+            .unwrap_or(&client);
+        let result = match Url::parse(&podcast.feed_url) {
+            Ok(url) => check_url(client, &url, Some(&headers))
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match result {
+            Ok(()) => report(
+                &Status::Pass,
+                &format!("Feed is reachable: {}", podcast.feed_url),
+                None,
+            ),
+            Err(err) => report(
+                &Status::Warn,
+                &format!("Feed is not reachable: {} ({})", podcast.feed_url, err),
+                Some("Check the feed URL in your config file."),
+            ),
+        }
+    }
+
+    success
+}
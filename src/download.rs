@@ -6,22 +6,103 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use crate::config::PodcastConfig;
+use crate::config::{Config, PodcastConfig, SortKey, DEFAULT_MAX_FEED_SIZE};
+use crate::context::AppContext;
+use crate::events::Event;
+use crate::extension::{
+    all_enclosures, attach_enclosures, fill_media_content_enclosures, find_extensions,
+    item_integrity, item_show_notes, namespace_prefix, next_page_url, parse_itunes_duration,
+    select_enclosure_quality, HashAlgorithm, Integrity, PODCAST_NAMESPACE,
+};
+use crate::health::format_cooldown;
+use crate::library::move_path;
+use crate::metadata::EpisodeMeta;
+use crate::naming::{
+    content_disposition_file_name, feed_url_slug, force_extension, make_unique,
+    render_subfolder_template, render_template, TemplateValues,
+};
+use crate::netrc::Netrc;
+use crate::state::{FetchResult, State};
+use crate::trace;
+use crate::tracking::{strip_tracking, DEFAULT_TRACKING_PREFIXES};
+use crate::units::parse_date;
+use colored::Colorize;
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
-use linya::Progress;
-use reqwest::{Client, Url};
-use std::io::Write;
-use std::path::PathBuf;
+use linya::{Bar, Progress};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use rss::extension::syndication::UpdatePeriod;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
 
 /// Represents a single episode that should be downloaded.
 #[derive(Debug)]
 pub struct EpisodeDownload {
+    pub feed_url: String,
+    /// Position of the podcast in the config, used to list the episodes in config order.
+    pub feed_index: usize,
+    /// Title of the podcast that the episode belongs to.
+    pub podcast_title: String,
+    /// Custom HTTP headers that are sent with each request (values are marked as sensitive).
+    pub headers: HeaderMap,
     pub guid: String,
+    pub title: Option<String>,
+    /// Publication date (as UNIX timestamp).
+    pub pub_date: Option<i64>,
+    /// Duration of the episode (in seconds).
+    pub duration: Option<u64>,
     pub url: Url,
+    /// Expected hash of the file from the feed (if any).
+    pub integrity: Option<Integrity>,
+    /// Alternative URLs of the enclosure on the configured mirrors, tried in order if the
+    /// download from `url` fails.
+    pub mirror_urls: Vec<Url>,
     pub file_size: Option<usize>,
     pub file_path: PathBuf,
+    pub sidecars: Vec<Sidecar>,
+    /// Previously downloaded file of the same episode that should be replaced.
+    pub replaces: Option<PathBuf>,
+    /// Metadata that is written next to the file (only with `save_metadata`).
+    pub metadata: Option<EpisodeMeta>,
+    /// Bandwidth limit that is shared by all episodes of the podcast (if any).
+    pub throttle: Option<Arc<Throttle>>,
+    /// Proxy URL that is used for the download (if any).
+    pub proxy: Option<String>,
+    /// The episode has already been downloaded (only returned with `include_downloaded`).
+    pub downloaded: bool,
+}
+
+/// Returns `url` with its scheme, host and port replaced by those of the `mirror` base URL.
+fn mirror_url(url: &Url, mirror: &str) -> Option<Url> {
+    let mirror = Url::parse(mirror).ok()?;
+    let mut mirror_url = url.clone();
+    mirror_url.set_scheme(mirror.scheme()).ok()?;
+    mirror_url.set_host(mirror.host_str()).ok()?;
+    mirror_url.set_port(mirror.port()).ok()?;
+    Some(mirror_url)
+}
+
+/// Extension of downloaded chapters, which replaces the extension of the episode file.
+pub const CHAPTERS_EXTENSION: &str = "chapters.json";
+
+/// Represents an additional file (e.g. chapters or a transcript) that is stored next to the
+/// downloaded episode.
+#[derive(Debug)]
+pub struct Sidecar {
+    /// Human-readable description of the file contents.
+    pub kind: &'static str,
+    pub url: Url,
+    /// Extension that replaces the extension of the episode file.
+    pub extension: String,
 }
 
 impl EpisodeDownload {
@@ -52,123 +133,1835 @@ pub fn to_human_size(size: usize) -> (usize, char) {
     }
 }
 
+/// Add the custom `headers` (if any) to the request.
+fn with_headers(request: RequestBuilder, headers: Option<&HeaderMap>) -> RequestBuilder {
+    match headers {
+        Some(headers) => request.headers(headers.clone()),
+        None => request,
+    }
+}
+
+/// Performs a `HEAD` request for `url` and returns the response if it was successful.
+pub async fn check_url(
+    client: &Client,
+    url: &Url,
+    headers: Option<&HeaderMap>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    trace::send(with_headers(client.head(url.as_str()), headers))
+        .await?
+        .error_for_status()
+}
+
 /// Returns the content length of the given `url` (or `None` on failure).
 ///
 /// *Note:* This performs a `HEAD` request.
-pub async fn retrieve_content_length(client: &Client, url: &Url) -> Option<usize> {
+pub async fn retrieve_content_length(
+    client: &Client,
+    url: &Url,
+    headers: Option<&HeaderMap>,
+) -> Option<usize> {
     // We need to determine the file size before we download so we can create a ProgressBar
     // A Header request for the CONTENT_LENGTH header gets us the file size
-    client
-        .head(url.as_str())
-        .send()
-        .await
-        .ok()
-        .and_then(|resp| {
-            if resp.status().is_success() {
-                resp.headers() // Gives is the HeaderMap
-                    .get(reqwest::header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
-                    .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
-                    .and_then(|ct_len| ct_len.parse().ok()) // Parses the Option as u64
-                    .and_then(|ct_len| if ct_len > 0 { Some(ct_len) } else { None })
-            } else {
-                None
-            }
-        })
+    check_url(client, url, headers).await.ok().and_then(|resp| {
+        resp.headers() // Gives is the HeaderMap
+            .get(reqwest::header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
+            .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
+            .and_then(|ct_len| ct_len.parse().ok()) // Parses the Option as u64
+            .and_then(|ct_len| if ct_len > 0 { Some(ct_len) } else { None })
+    })
+}
+
+/// A file that should be downloaded.
+#[derive(Debug)]
+pub struct FileRequest<'a> {
+    pub url: &'a Url,
+    /// Custom HTTP headers that are sent with the request.
+    pub headers: Option<&'a HeaderMap>,
+    /// Expected file size (e.g. from the feed).
+    pub file_size: Option<usize>,
+    /// Bandwidth limit for the download (if any).
+    pub throttle: Option<&'a Throttle>,
+    /// Maximum size of the response body (in bytes), larger responses are aborted.
+    pub max_size: Option<usize>,
+    /// Counter that the number of received bytes is added to (e.g. to measure the throughput).
+    pub transferred: Option<&'a AtomicUsize>,
+}
+
+/// Where the progress of a download is displayed.
+#[derive(Clone, Debug)]
+pub struct ProgressDisplay<'a> {
+    /// Progress bars to add the bar for this download to (or `None` to display nothing).
+    pub multibar: Option<Arc<Mutex<Progress>>>,
+    /// Label of the progress bar for this download.
+    pub label: &'a str,
+    /// Bar that tracks the combined progress of all downloads (if any).
+    pub total_bar: Option<&'a Bar>,
 }
 
 /// Download a file and display a progress bar for it.
 ///
-/// If no `file_size` is specified, this tries to determine the file size from the `Content-Length`
-/// header automatically.
+/// The size of the progress bar is determined from the `Content-Length` header. The `file_size`
+/// of the `request` (e.g. from the feed) is only used if the server does not report a size. If
+/// more data than expected arrives, the progress bar switches to an indeterminate state. If the
+/// size is unknown altogether (e.g. for chunked responses), the bar is only shown once the
+/// download has finished.
+///
+/// If `compute_hash` is set, the SHA-256 hash of the downloaded data is computed while
+/// downloading and returned as hex string.
 pub async fn download_file(
+    client: &Client,
     data: &mut impl Write,
-    multibar: Arc<Mutex<Progress>>,
-    url: &Url,
-    file_size: Option<usize>,
-    label: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a reqwest Client
-    let client = Client::new();
+    host_limiter: &HostLimiter,
+    request: &FileRequest<'_>,
+    compute_hash: bool,
+    progress: ProgressDisplay<'_>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let FileRequest {
+        url,
+        headers,
+        file_size,
+        throttle,
+        max_size,
+        transferred,
+    } = *request;
+    let ProgressDisplay {
+        multibar,
+        label,
+        total_bar,
+    } = progress;
 
-    let file_size = match file_size {
-        Some(_) => file_size,
-        None => retrieve_content_length(&client, url).await,
-    };
+    // Hold the permit until the download is finished, so that we don't open too many
+    // connections to the same host.
+    let _permit = host_limiter.acquire(url).await;
 
-    // Here we build the actual Request with a RequestBuilder from the Client
-    let request = client.get(url.as_str());
+    let content_length = retrieve_content_length(client, url, headers).await;
+    if let (Some(size), Some(max_size)) = (content_length, max_size) {
+        if size > max_size {
+            return Err(too_large_error(max_size).into());
+        }
+    }
 
-    // Create the ProgressBar with the acquired size from before
-    // and add it to the multibar
-    let bar_size = file_size.unwrap_or(1);
-    let progress_bar = multibar.lock().await.bar(bar_size, label);
+    // Here we build the actual Request with a RequestBuilder from the Client
+    let request = with_headers(client.get(url.as_str()), headers);
 
     // Do the actual request to download the file
-    let mut download = request.send().await?;
+    let mut download = trace::send(request).await?;
+    if download.status().is_client_error() || download.status().is_server_error() {
+        return Err(status_error(download).await);
+    }
+    let mut hasher = compute_hash.then(Sha256::new);
+    let mut downloaded_size = 0;
+
+    // The length from the feed is often wrong, so we prefer the size reported by the server.
+    // Servers that don't answer HEAD requests may still send a Content-Length for GET requests.
+    let file_size = content_length
+        .or_else(|| {
+            download
+                .content_length()
+                .filter(|&size| size > 0)
+                .map(|size| size as usize)
+        })
+        .or(file_size);
+
+    // Create the ProgressBar with the acquired size from before and add it to the multibar.
+    //
+    // If the size is still unknown (e.g. for chunked responses), there is nothing to show a
+    // percentage for, so the bar is only added after the download has finished.
+    let mut progress_bar = BarGuard {
+        multibar: multibar.clone(),
+        bar: match (&multibar, file_size) {
+            (Some(multibar), Some(bar_size)) if bar_size > 0 => {
+                Some(multibar.lock().await.bar(bar_size, label))
+            }
+            _ => None,
+        },
+    };
 
     // Do an asynchronous, buffered copy of the download to the output file.
     //
     // We use the part from the reqwest-tokio example here on purpose
-    // This way, we are able to increase the ProgressBar with every downloaded chunk
+    // This way, we are able to increase the ProgressBar with every downloaded chunk. The next
+    // chunk is only requested once the previous one has been written, so a slow disk slows down
+    // the download instead of piling up chunks in memory.
+    let mut data = BufWriter::with_capacity(WRITE_BUFFER_SIZE, data);
     while let Some(chunk) = download.chunk().await? {
-        multibar
+        if max_size.is_some_and(|max_size| downloaded_size + chunk.len() > max_size) {
+            return Err(too_large_error(max_size.unwrap_or_default()).into());
+        }
+        if let Some(throttle) = throttle {
+            throttle.consume(chunk.len()).await;
+        }
+        downloaded_size += chunk.len();
+        if let Some(transferred) = transferred {
+            transferred.fetch_add(chunk.len(), Ordering::Relaxed);
+        }
+        if let Some(multibar) = &multibar {
+            let mut multibar = multibar.lock().await;
+            if let Some(total_bar) = total_bar {
+                multibar.inc_and_draw(total_bar, chunk.len());
+            }
+            if let Some(bar) = progress_bar.bar.take() {
+                if file_size.is_some_and(|bar_size| downloaded_size > bar_size) {
+                    // Bars cannot be resized, so we cancel it instead of showing a completed bar
+                    // while the download is still running.
+                    multibar.cancel(bar);
+                } else {
+                    multibar.inc_and_draw(&bar, chunk.len());
+                    progress_bar.bar = Some(bar);
+                }
+            }
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&chunk);
+        }
+        // Write chunk to output file. The caller is responsible for removing the incomplete
+        // output (e.g. by dropping the `PartialFile`).
+        data.write_all(&chunk)
+            .map_err(|err| write_error(err, downloaded_size - chunk.len(), file_size))?;
+    }
+    // Most write errors (e.g. a full disk) only show up when the buffer is written out.
+    if let Err(err) = data.flush() {
+        let written = downloaded_size - data.buffer().len();
+        return Err(write_error(err, written, file_size).into());
+    }
+
+    if let (Some(multibar), None) = (&multibar, file_size) {
+        if downloaded_size > 0 {
+            let mut multibar = multibar.lock().await;
+            let bar = multibar.bar(downloaded_size, label);
+            multibar.set_and_draw(&bar, downloaded_size);
+        }
+    }
+
+    Ok(hasher.map(|hasher| format!("{:x}", hasher.finalize())))
+}
+
+/// Size of the buffer that is used to write downloaded data to the output (in bytes).
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Returns the error for a failed write after `written` of the expected `file_size` bytes.
+fn write_error(err: std::io::Error, written: usize, file_size: Option<usize>) -> std::io::Error {
+    std::io::Error::new(
+        err.kind(),
+        format!(
+            "Failed to write to file after {} of {} bytes: {}",
+            written,
+            file_size.map_or_else(|| String::from("?"), |size| size.to_string()),
+            err
+        ),
+    )
+}
+
+/// Returns the error for a response that is larger than `max_size`.
+fn too_large_error(max_size: usize) -> std::io::Error {
+    let (human_size, human_size_suffix) = to_human_size(max_size);
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Response is larger than the limit of {}{}",
+            human_size, human_size_suffix
+        ),
+    )
+}
+
+/// Cancels the progress bar of a download if it is dropped before the download is complete, e.g.
+/// because the download failed or the future was cancelled by a timeout.
+struct BarGuard {
+    multibar: Option<Arc<Mutex<Progress>>>,
+    bar: Option<Bar>,
+}
+
+impl Drop for BarGuard {
+    fn drop(&mut self) {
+        if let (Some(multibar), Some(bar)) = (&self.multibar, self.bar.take()) {
+            // We cannot wait for the lock here. If it is held elsewhere, the bar is left as it is.
+            if let Some(mut multibar) = multibar.try_lock() {
+                if !multibar.is_done(&bar) {
+                    multibar.cancel(bar);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the path of the `.part` file next to `path`.
+fn sibling_part_path(path: &Path) -> PathBuf {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".part");
+    path.with_file_name(temp_name)
+}
+
+/// A file that is written to a temporary `.part` file next to its final location (or in a
+/// separate temporary directory).
+///
+/// The temporary file is only moved to its final location by [`PartialFile::finish`]. If the
+/// `PartialFile` is dropped before (e.g. because the download failed or was cancelled), the
+/// temporary file is deleted, so that no incomplete file is left behind and a previously
+/// downloaded file at the final location stays intact.
+#[derive(Debug)]
+pub struct PartialFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<std::fs::File>,
+}
+
+impl PartialFile {
+    /// Create the temporary file for `path`, either next to it or in `temp_dir`.
+    pub fn create(path: &Path, temp_dir: Option<&Path>) -> std::io::Result<Self> {
+        let temp_path = match temp_dir {
+            Some(temp_dir) => {
+                std::fs::create_dir_all(temp_dir)?;
+                // Episodes of different podcasts may have the same file name, so the name of the
+                // temporary file also contains a hash of the final location.
+                let mut hasher = DefaultHasher::new();
+                path.hash(&mut hasher);
+                let mut temp_name = std::ffi::OsString::from(format!("{:016x}-", hasher.finish()));
+                temp_name.push(path.file_name().unwrap_or_default());
+                temp_name.push(".part");
+                temp_dir.join(temp_name)
+            }
+            None => sibling_part_path(path),
+        };
+        // The file is also opened for reading, so that it can be verified after the download.
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            temp_path,
+            file: Some(file),
+        })
+    }
+
+    /// Returns the temporary file for writing.
+    pub fn file(&mut self) -> &mut std::fs::File {
+        // Unwrap is safe here, because the file is only taken when the `PartialFile` is consumed.
+        self.file.as_mut().unwrap()
+    }
+
+    /// Move the temporary file to its final location.
+    ///
+    /// If the temporary file is on another filesystem, it is first copied next to the final
+    /// location, so that the final file still appears atomically.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        if std::fs::rename(&self.temp_path, &self.path).is_ok() {
+            return Ok(());
+        }
+
+        let part_path = sibling_part_path(&self.path);
+        let result = move_path(&self.temp_path, &part_path)
+            .and_then(|_| std::fs::rename(&part_path, &self.path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&self.temp_path);
+            let _ = std::fs::remove_file(&part_path);
+        }
+        result
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Add an `Authorization` header with the credentials for the host of `url` from `netrc`.
+///
+/// Explicitly configured credentials take precedence, i.e. nothing is added if `headers` already
+/// contain an `Authorization` header or the URL contains a user name.
+pub fn add_netrc_credentials(headers: &mut HeaderMap, netrc: Option<&Netrc>, url: &Url) {
+    if headers.contains_key(reqwest::header::AUTHORIZATION) || !url.username().is_empty() {
+        return;
+    }
+    let credentials = match netrc.zip(url.host_str()) {
+        Some((netrc, host)) => netrc.credentials(host),
+        None => None,
+    };
+    if let Some(credentials) = credentials {
+        let token =
+            base64_encode(format!("{}:{}", credentials.login, credentials.password).as_bytes());
+        if let Ok(mut value) = HeaderValue::from_str(&format!("Basic {}", token)) {
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+    }
+}
+
+/// Encode `data` as base64 (with padding).
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns `true` if the contents of `file` match the expected `integrity` hash.
+///
+/// The file is read from the beginning, regardless of its current position.
+pub fn verify_integrity(file: &mut std::fs::File, integrity: &Integrity) -> std::io::Result<bool> {
+    use std::io::{Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(0))?;
+    let digest = match integrity.algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            std::io::copy(file, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }
+        HashAlgorithm::Sha384 => {
+            let mut hasher = Sha384::new();
+            std::io::copy(file, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }
+        HashAlgorithm::Sha512 => {
+            let mut hasher = Sha512::new();
+            std::io::copy(file, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }
+    };
+    // Some feeds omit the padding.
+    Ok(base64_encode(&digest).trim_end_matches('=') == integrity.digest.trim_end_matches('='))
+}
+
+/// Limits the number of simultaneous connections to a single host.
+///
+/// This protects small (e.g. self-hosted) servers when a high `max_parallel_downloads` value is
+/// used.
+#[derive(Debug)]
+pub struct HostLimiter {
+    max_connections: usize,
+    semaphores: std::sync::Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    /// Create a new limiter that allows at most `max_connections` connections per host.
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            semaphores: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a connection to the host of `url` is allowed.
+    ///
+    /// The connection slot is released when the returned permit is dropped.
+    pub async fn acquire(&self, url: &Url) -> OwnedSemaphorePermit {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        let semaphore = self
+            .semaphores
             .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)))
+            .clone();
+        // Unwrap is safe here, because the semaphore is never closed.
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+/// Limits the download speed with a token bucket.
+///
+/// The same throttle can be shared by multiple downloads, which then share the bandwidth.
+#[derive(Debug)]
+pub struct Throttle {
+    /// Allowed download speed (in bytes per second).
+    bytes_per_sec: f64,
+    /// Available bytes and the time they were last refilled.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    /// Create a new throttle that allows `kbps` kilobits per second.
+    pub fn from_kbps(kbps: usize) -> Self {
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Self {
+            bytes_per_sec,
+            // Start with a full bucket, so that short bursts (e.g. the first chunk) are not delayed.
+            bucket: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    /// Wait until `bytes` may be downloaded.
+    ///
+    /// Waiting happens while holding the lock, so that concurrent downloads are delayed in turn
+    /// instead of all overdrawing the bucket at once.
+    pub async fn consume(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().await;
+        let (available, refilled_at) = &mut *bucket;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+        // The bucket holds at most one second worth of data.
+        *available = (*available + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        *refilled_at = now;
+        *available -= bytes as f64;
+        if *available < 0.0 {
+            let delay = Duration::from_secs_f64(-*available / self.bytes_per_sec);
+            tokio::time::sleep(delay).await;
+            *available = 0.0;
+            *refilled_at = Instant::now();
+        }
+    }
+}
+
+/// Time between two throughput measurements of a [`ConcurrencyController`].
+pub const CONCURRENCY_SAMPLE_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Minimum relative throughput gain for keeping an additional parallel download.
+const CONCURRENCY_MIN_GAIN: f64 = 1.1;
+
+/// Number of samples that the controller waits after backing off before it tries a higher
+/// limit again.
+const CONCURRENCY_HOLD_SAMPLES: usize = 5;
+
+/// Adjusts the number of parallel downloads to the measured throughput.
+///
+/// Downloads start one at a time. After each [`CONCURRENCY_SAMPLE_INTERVAL`], the limit is
+/// raised by one if the throughput improved noticeably, and lowered again if it did not. If
+/// downloads failed since the last sample, the limit is halved.
+#[derive(Debug)]
+pub struct ConcurrencyController {
+    semaphore: Semaphore,
+    /// Number of received bytes, see [`FileRequest::transferred`].
+    transferred: AtomicUsize,
+    failures: AtomicUsize,
+    inner: std::sync::Mutex<ControllerState>,
+}
+
+/// Mutable state of a [`ConcurrencyController`].
+#[derive(Debug)]
+struct ControllerState {
+    max_jobs: usize,
+    /// Current limit of parallel downloads.
+    limit: usize,
+    /// Number of permits that were in use when the limit was lowered, and that are forgotten as
+    /// soon as their downloads finish.
+    excess_permits: usize,
+    last_sample: Instant,
+    last_transferred: usize,
+    last_failures: usize,
+    /// Throughput (in bytes per second) at the current limit before it was last raised.
+    baseline: Option<f64>,
+    /// Remaining samples before the limit may be raised again.
+    hold: usize,
+}
+
+/// A download slot of a [`ConcurrencyController`], which is released when it is dropped.
+#[derive(Debug)]
+pub struct ConcurrencyPermit<'a> {
+    controller: &'a ConcurrencyController,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.controller.inner.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            // The permit is forgotten instead of being handed to the next waiting download if
+            // the limit has been lowered while it was in use.
+            if inner.excess_permits > 0 {
+                inner.excess_permits -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl ConcurrencyController {
+    /// Create a new controller that allows at most `max_jobs` parallel downloads.
+    pub fn new(max_jobs: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            transferred: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            inner: std::sync::Mutex::new(ControllerState {
+                max_jobs: max_jobs.max(1),
+                limit: 1,
+                excess_permits: 0,
+                last_sample: Instant::now(),
+                last_transferred: 0,
+                last_failures: 0,
+                baseline: None,
+                hold: 0,
+            }),
+        }
+    }
+
+    /// Wait until another download may be started.
+    pub async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        // Unwrap is safe here, because the semaphore is never closed.
+        let permit = self.semaphore.acquire().await.unwrap();
+        ConcurrencyPermit {
+            controller: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// Returns the counter that the downloads add the received bytes to.
+    pub fn transferred(&self) -> &AtomicUsize {
+        &self.transferred
+    }
+
+    /// Record that a download failed.
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current limit of parallel downloads.
+    ///
+    /// After the limit has been lowered, more downloads may still be running until enough of
+    /// them have finished.
+    pub fn limit(&self) -> usize {
+        self.inner.lock().unwrap().limit
+    }
+
+    /// Lower the limit by `count`, but not below one.
+    fn lower_limit(&self, inner: &mut ControllerState, count: usize) {
+        let count = count.min(inner.limit - 1);
+        inner.limit -= count;
+        // Permits that are in use are forgotten once they are released.
+        inner.excess_permits += count - self.semaphore.forget_permits(count);
+    }
+
+    /// Measure the throughput since the last call and adjust the limit.
+    pub fn adjust(&self) {
+        self.sample(Instant::now());
+    }
+
+    /// Measure the throughput between the last sample and `now` and adjust the limit.
+    fn sample(&self, now: Instant) {
+        let transferred = self.transferred.load(Ordering::Relaxed);
+        let failures = self.failures.load(Ordering::Relaxed);
+        let mut inner = self.inner.lock().unwrap();
+        let elapsed = now.duration_since(inner.last_sample).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let throughput = (transferred - inner.last_transferred) as f64 / elapsed;
+        let failed = failures > inner.last_failures;
+        inner.last_sample = now;
+        inner.last_transferred = transferred;
+        inner.last_failures = failures;
+
+        if failed {
+            let limit = inner.limit;
+            self.lower_limit(&mut inner, limit / 2);
+            inner.baseline = None;
+            inner.hold = CONCURRENCY_HOLD_SAMPLES;
+            return;
+        }
+        match inner.baseline {
+            // The last increase did not pay off, so it is reverted.
+            Some(baseline) if throughput < baseline * CONCURRENCY_MIN_GAIN => {
+                self.lower_limit(&mut inner, 1);
+                inner.baseline = None;
+                inner.hold = CONCURRENCY_HOLD_SAMPLES;
+            }
+            // The throughput is not measured at the lowered limit yet.
+            _ if inner.excess_permits > 0 => (),
+            _ if inner.hold > 0 => inner.hold -= 1,
+            _ if inner.limit < inner.max_jobs => {
+                inner.limit += 1;
+                self.semaphore.add_permits(1);
+                inner.baseline = Some(throughput);
+            }
+            _ => inner.baseline = None,
+        }
+    }
+}
+
+/// Options for a single sync run that are set on the command line.
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    /// Print additional information.
+    pub verbose: bool,
+    /// Download episodes again even if they already exist or have been downloaded before.
+    pub force_redownload: bool,
+    /// Only download episodes whose GUIDs were not in the feed during the previous sync,
+    /// regardless of the downloaded files.
+    pub only_new: bool,
+    /// Also return the episodes that have already been downloaded (marked as `downloaded`).
+    pub include_downloaded: bool,
+    /// Maximum number of episodes that are downloaded from all feeds combined (newest first).
+    pub max_episodes_total: Option<usize>,
+    /// Only download the episode with this GUID, even if it is older than the episode limit or
+    /// has been downloaded before.
+    pub guid: Option<String>,
+    /// Only fetch the podcast with this feed URL.
+    pub feed_url: Option<String>,
+    /// Episode limit that overrides the configured limits of all podcasts (`0` means unlimited).
+    pub episode_limit: Option<usize>,
+    /// Fetch feeds even if their update interval has not elapsed since the last fetch.
+    pub ignore_ttl: bool,
+    /// Only record the new episodes as pending in the state, without downloading them.
+    pub resolve_only: bool,
+    /// Fetch all feeds, even if their update interval has not elapsed or they are backed off
+    /// after failing repeatedly.
+    pub force: bool,
+    /// Print machine-readable events instead of progress bars.
+    pub json_lines: bool,
+    /// Don't show any progress, only the summary at the end.
+    pub summary_only: bool,
+    /// File that the report of the sync is written to (if any).
+    pub report_path: Option<PathBuf>,
+    /// Continue the saved download queue of an interrupted sync instead of fetching the feeds.
+    pub resume: bool,
+    /// Cancel the remaining downloads after the first failed one.
+    pub fail_fast: bool,
+    /// Adjust the number of parallel downloads to the measured throughput (up to
+    /// `max_parallel_downloads`).
+    pub concurrency_auto: bool,
+    /// Only download episodes that were published after the start of the last successful sync.
+    pub since_last_run: bool,
+}
+
+/// Episodes that were skipped during a sync run, because they have already been downloaded or
+/// exceed `--max-episodes-total`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SkippedEpisodes {
+    /// Number of episodes that have already been downloaded.
+    pub count: usize,
+    /// Combined size of the downloaded episodes (in bytes), as declared in the feeds.
+    pub size: usize,
+    /// Number of episodes that were not downloaded, because they exceed the total episode limit.
+    pub over_limit: usize,
+}
+
+/// Returns `true` if the value of an `itunes:explicit` tag marks the content as explicit.
+fn is_explicit(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "yes" | "true" | "explicit"
+    )
+}
+
+/// Value of the `Accept` header for feed requests (unless a custom one is configured).
+const FEED_ACCEPT: &str =
+    "application/rss+xml, application/atom+xml, application/xml;q=0.9, */*;q=0.8";
+
+/// Returns the categories and `itunes:keywords` of `item` (in lowercase).
+fn item_categories(item: &rss::Item) -> Vec<String> {
+    let keywords = item
+        .itunes_ext
+        .as_ref()
+        .and_then(|ext| ext.keywords.as_deref())
+        .into_iter()
+        .flat_map(|keywords| keywords.split(','));
+    item.categories
+        .iter()
+        .map(|category| category.name.as_str())
+        .chain(keywords)
+        .map(|category| category.trim().to_lowercase())
+        .filter(|category| !category.is_empty())
+        .collect()
+}
+
+/// Returns `true` if an item with the given `categories` (in lowercase) should be downloaded.
+///
+/// If `include` is set, the item needs to have at least one of its categories. Items with any of
+/// the `exclude` categories are skipped. Categories are compared case-insensitively.
+fn matches_categories(
+    categories: &[String],
+    include: Option<&[String]>,
+    exclude: Option<&[String]>,
+) -> bool {
+    let contains = |wanted: &[String]| {
+        wanted
+            .iter()
+            .any(|wanted| categories.contains(&wanted.trim().to_lowercase()))
+    };
+    include.is_none_or(contains) && !exclude.is_some_and(contains)
+}
+
+/// Number of times a feed page is requested if the server responds with an empty body or the
+/// request fails with a transient error.
+const FEED_PAGE_ATTEMPTS: usize = 2;
+
+/// Time to wait before requesting a feed page again after an empty response or transient error.
+const FEED_PAGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of bytes of an error page that are checked for bot challenge markers.
+const CHALLENGE_SNIFF_SIZE: usize = 64 * 1024;
+
+/// Parts of the challenge pages of common bot protection services (in lowercase).
+const CHALLENGE_MARKERS: &[&str] = &[
+    "cf-browser-verification",
+    "cf_chl_",
+    "/cdn-cgi/challenge-platform/",
+    "<title>just a moment...</title>",
+    "<title>attention required! | cloudflare</title>",
+    "ddos-guard",
+    "sucuri website firewall",
+    "_incapsula_resource",
+    "awswaf",
+];
+
+/// A request was answered with the challenge page of a bot protection service instead of the feed
+/// or file.
+#[derive(Debug)]
+pub struct BotChallengeError {
+    /// HTTP status code of the response (if it was an error).
+    pub status: Option<StatusCode>,
+}
+
+impl std::fmt::Display for BotChallengeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server responded with a bot challenge")?;
+        if let Some(status) = self.status {
+            write!(f, " (HTTP {})", status.as_u16())?;
+        }
+        write!(
+            f,
+            "; a browser-like `User-Agent` or `Cookie` in the podcast's `headers` may be required"
+        )
+    }
+}
+
+impl std::error::Error for BotChallengeError {}
+
+/// Returns `true` if the response with the given `headers` and (beginning of the) `body` is the
+/// challenge page of a bot protection service.
+fn is_bot_challenge(headers: &HeaderMap, body: &[u8]) -> bool {
+    if headers
+        .get("cf-mitigated")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"challenge"))
+    {
+        return true;
+    }
+    let body =
+        String::from_utf8_lossy(&body[..body.len().min(CHALLENGE_SNIFF_SIZE)]).to_lowercase();
+    CHALLENGE_MARKERS.iter().any(|marker| body.contains(marker))
+}
+
+/// Returns the error for a failed `response`, which is a [`BotChallengeError`] if its body is the
+/// challenge page of a bot protection service.
+///
+/// Only the beginning of the error page is read, and only for the status codes that these services
+/// use.
+async fn status_error(mut response: reqwest::Response) -> Box<dyn std::error::Error> {
+    let status = response.status();
+    // Unwrap is safe here, because the caller only passes failed responses.
+    let err = response.error_for_status_ref().unwrap_err();
+    if !matches!(status.as_u16(), 403 | 429 | 503) {
+        return err.into();
+    }
+    let mut body = Vec::new();
+    while body.len() < CHALLENGE_SNIFF_SIZE {
+        match response.chunk().await {
+            Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+            _ => break,
+        }
+    }
+    if is_bot_challenge(response.headers(), &body) {
+        Box::new(BotChallengeError {
+            status: Some(status),
+        })
+    } else {
+        err.into()
+    }
+}
+
+/// Returns the HTTP status code of the response that a request failed with (if any).
+pub fn error_status(err: &(dyn std::error::Error + 'static)) -> Option<StatusCode> {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .or_else(|| {
+            err.downcast_ref::<BotChallengeError>()
+                .and_then(|challenge| challenge.status)
+        })
+}
+
+/// Returns `true` if a failed request may succeed when it is repeated.
+///
+/// Connection errors, timeouts, `5xx` responses and `429 Too Many Requests` are transient. Other
+/// `4xx` responses (e.g. `404 Not Found` or `401 Unauthorized`) and parse errors are permanent.
+pub fn is_retryable(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(status) = error_status(err) {
+        return status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+    }
+    if err.is::<tokio::time::error::Elapsed>() {
+        return true;
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return err.is_timeout() || err.is_connect() || err.is_request() || err.is_body();
+    }
+    err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+        matches!(
+            err.kind(),
+            std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::Interrupted
+        )
+    })
+}
+
+/// Returns `true` if a request for `url` with `headers` carried credentials and failed with
+/// `401 Unauthorized` or `403 Forbidden`.
+///
+/// The same credentials are sent to the mirrors, so they would be rejected there as well.
+pub fn is_credentials_rejected(
+    err: &(dyn std::error::Error + 'static),
+    url: &Url,
+    headers: &HeaderMap,
+) -> bool {
+    let has_credentials = !url.username().is_empty()
+        || url.password().is_some()
+        || headers.contains_key(reqwest::header::AUTHORIZATION);
+    has_credentials
+        && matches!(
+            error_status(err),
+            Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        )
+}
+
+/// Downloads and parses podcast feeds.
+struct FeedFetcher<'a> {
+    multibar: Option<Arc<Mutex<Progress>>>,
+    host_limiter: &'a HostLimiter,
+    /// Timeout for fetching a single feed page.
+    timeout: Duration,
+    /// Maximum number of pages that are fetched for paginated feeds.
+    max_pages: usize,
+    /// Maximum size of a single feed page (in bytes).
+    max_size: usize,
+    /// Credentials from the `.netrc` file (if enabled).
+    netrc: Option<&'a Netrc>,
+}
+
+impl FeedFetcher<'_> {
+    /// Download and parse a single feed page.
+    ///
+    /// Fails if the page could not be downloaded within the timeout. Empty responses and
+    /// [retryable](is_retryable) errors are retried once after a short delay, because some servers
+    /// occasionally respond with an empty body or fail temporarily.
+    async fn fetch_page(
+        &self,
+        client: &Client,
+        url: &Url,
+        headers: &HeaderMap,
+        label: &str,
+    ) -> Result<rss::Channel, Box<dyn std::error::Error>> {
+        // Servers that negotiate the content type might otherwise respond with an HTML page.
+        let mut headers = headers.clone();
+        headers
+            .entry(reqwest::header::ACCEPT)
+            .or_insert(HeaderValue::from_static(FEED_ACCEPT));
+        add_netrc_credentials(&mut headers, self.netrc, url);
+        let headers = &headers;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut data: Vec<u8> = Vec::new();
+            let result = match tokio::time::timeout(
+                self.timeout,
+                download_file(
+                    client,
+                    &mut data,
+                    self.host_limiter,
+                    &FileRequest {
+                        url,
+                        headers: Some(headers),
+                        file_size: None,
+                        throttle: None,
+                        max_size: Some(self.max_size),
+                        transferred: None,
+                    },
+                    false,
+                    ProgressDisplay {
+                        multibar: self.multibar.clone(),
+                        label,
+                        total_bar: None,
+                    },
+                ),
+            )
             .await
-            .inc_and_draw(&progress_bar, chunk.len());
-        data.write_all(&chunk)?; // Write chunk to output file
+            {
+                Ok(result) => result,
+                Err(elapsed) => Err(elapsed.into()),
+            };
+            match result {
+                Err(err) if attempts < FEED_PAGE_ATTEMPTS && is_retryable(err.as_ref()) => {
+                    tokio::time::sleep(FEED_PAGE_RETRY_DELAY).await;
+                    continue;
+                }
+                result => result?,
+            };
+
+            if !data.iter().all(u8::is_ascii_whitespace) {
+                // Some services answer with a challenge page and status 200.
+                let mut channel = rss::Channel::read_from(&data[..]).map_err(|err| {
+                    if is_bot_challenge(&HeaderMap::new(), &data) {
+                        Box::new(BotChallengeError { status: None }) as Box<dyn std::error::Error>
+                    } else {
+                        err.into()
+                    }
+                })?;
+                attach_enclosures(&mut channel, &data);
+                fill_media_content_enclosures(&mut channel);
+                return Ok(channel);
+            }
+            if attempts >= FEED_PAGE_ATTEMPTS {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Feed returned an empty response",
+                )));
+            }
+            tokio::time::sleep(FEED_PAGE_RETRY_DELAY).await;
+        }
+    }
+
+    /// Download and parse the podcast feed at `feed_url`.
+    ///
+    /// If the feed is paginated and the first page contains less than `episode_limit` items (or
+    /// there is no limit), the following pages are fetched as well and their items are appended.
+    async fn fetch(
+        &self,
+        client: &Client,
+        feed_url: &str,
+        headers: &HeaderMap,
+        label: &str,
+        episode_limit: Option<usize>,
+    ) -> Result<rss::Channel, Box<dyn std::error::Error>> {
+        let mut url = Url::parse(feed_url)?;
+        let mut channel = self.fetch_page(client, &url, headers, label).await?;
+
+        let mut visited_urls = HashSet::from([url.clone()]);
+        for page in 2..=self.max_pages {
+            if episode_limit.is_some_and(|limit| channel.items.len() >= limit) {
+                break;
+            }
+
+            url = match next_page_url(&channel, &url) {
+                Some(next_url) if visited_urls.insert(next_url.clone()) => next_url,
+                _ => break,
+            };
+            let page_label = format!("{} (page {})", label, page);
+            match self.fetch_page(client, &url, headers, &page_label).await {
+                Ok(next_page) => {
+                    // The link of the next page is used to continue the pagination.
+                    channel.extensions = next_page.extensions;
+                    channel.namespaces = next_page.namespaces;
+                    channel.items.extend(next_page.items);
+                }
+                Err(err) => {
+                    eprintln!(
+                        "{}",
+                        format!("Failed to fetch feed page {}: {}", url, err).yellow()
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(channel)
+    }
+}
+
+/// Returns the name of the directory that the episodes of `podcast` are stored in.
+///
+/// Some feeds have an empty `<title>`, which would result in the episodes being stored directly
+/// in the download directory, so the host of the feed URL is used in that case.
+pub fn podcast_dir_name(podcast: &PodcastConfig, channel_title: &str) -> String {
+    podcast
+        .title
+        .as_deref()
+        .filter(|title| !title.trim().is_empty())
+        .or_else(|| Some(channel_title).filter(|title| !title.trim().is_empty()))
+        .map_or_else(|| feed_url_slug(&podcast.feed_url), str::to_owned)
+}
+
+/// Download and parse the complete feeds (i.e. all pages) of the given `podcasts`.
+///
+/// Unlike `fetch_sync_info`, this ignores the update intervals and does not modify the state.
+pub async fn fetch_channels<'a>(
+    context: &AppContext,
+    podcasts: &'a [PodcastConfig],
+) -> Vec<(
+    &'a PodcastConfig,
+    Result<rss::Channel, Box<dyn std::error::Error>>,
+)> {
+    let config = &context.config;
+    let host_limiter = HostLimiter::new(config.max_connections_per_host.unwrap_or(4));
+    eprintln!("Fetching podcast feeds...");
+    let fetcher = FeedFetcher {
+        multibar: Some(Arc::new(Mutex::new(linya::Progress::new()))),
+        host_limiter: &host_limiter,
+        timeout: Duration::from_secs(config.feed_timeout_secs.unwrap_or(15)),
+        max_pages: config.max_feed_pages.unwrap_or(10),
+        max_size: config.max_feed_size.unwrap_or(DEFAULT_MAX_FEED_SIZE),
+        netrc: context.netrc.as_ref(),
+    };
+    let fetcher = &fetcher;
+    let task_count = podcasts.len();
+    futures::stream::iter(podcasts)
+        .enumerate()
+        .map(|(i, podcast)| async move {
+            let label = format!("({}/{}) {}", i + 1, task_count, &podcast.feed_url);
+            // The headers have been validated when loading the config.
+            let headers = podcast.header_map().unwrap_or_default();
+            let result = fetcher
+                .fetch(
+                    context.client_for(podcast.proxy.as_deref()),
+                    &podcast.feed_url,
+                    &headers,
+                    &label,
+                    None,
+                )
+                .await;
+            (podcast, result)
+        })
+        .buffered(config.max_parallel_feeds.unwrap_or(10))
+        .collect()
+        .await
+}
+
+/// Returns the maximum number of episodes to download for `podcast` (or `None` if unlimited).
+fn episode_limit(config: &Config, podcast: &PodcastConfig) -> Option<usize> {
+    match podcast.episode_limit.or(config.episode_limit).unwrap_or(1) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// Returns the file extension for a `<podcast:transcript>` element.
+///
+/// The extension is derived from the `type` attribute if possible (using the `mime_extensions`
+/// from the config first), and from the URL otherwise.
+fn transcript_extension(
+    mime_type: Option<&str>,
+    url: &Url,
+    mime_extensions: Option<&HashMap<String, String>>,
+) -> String {
+    let configured =
+        mime_type.and_then(|mime_type| configured_extension(mime_type, mime_extensions));
+    let extension = configured.or(match mime_type {
+        Some("text/vtt") => Some("vtt"),
+        Some("application/x-subrip" | "application/srt" | "text/srt") => Some("srt"),
+        Some("application/json") => Some("json"),
+        Some("text/html") => Some("html"),
+        Some("text/plain") => Some("txt"),
+        _ => None,
+    });
+
+    extension
+        .map(str::to_owned)
+        .or_else(|| {
+            Path::new(url.path())
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(str::to_owned)
+        })
+        .unwrap_or_else(|| String::from("txt"))
+}
+
+/// Returns the extension for the given MIME type from the `mime_extensions` of the config (if
+/// any).
+///
+/// Parameters (e.g. `; charset=utf-8`) and the case of the MIME type are ignored.
+fn configured_extension<'a>(
+    mime_type: &str,
+    mime_extensions: Option<&'a HashMap<String, String>>,
+) -> Option<&'a str> {
+    let mime_type = mime_type.split(';').next().unwrap_or_default().trim();
+    mime_extensions?
+        .iter()
+        .find(|(configured, _)| configured.trim().eq_ignore_ascii_case(mime_type))
+        .map(|(_, extension)| extension.trim_start_matches('.'))
+}
+
+/// Returns the file extension for an episode with the given MIME type.
+///
+/// The `mime_extensions` from the config take precedence over the built-in mapping.
+fn media_extension<'a>(
+    mime_type: &str,
+    mime_extensions: Option<&'a HashMap<String, String>>,
+) -> Option<&'a str> {
+    if let Some(extension) = configured_extension(mime_type, mime_extensions) {
+        return Some(extension);
+    }
+    let mime_type = mime_type.split(';').next().unwrap_or_default().trim();
+    match mime_type.to_ascii_lowercase().as_str() {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/mp4" | "audio/x-m4a" | "audio/m4a" => Some("m4a"),
+        "audio/aac" | "audio/x-aac" => Some("aac"),
+        "audio/ogg" => Some("ogg"),
+        "audio/opus" => Some("opus"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/wav" | "audio/x-wav" => Some("wav"),
+        "video/mp4" => Some("mp4"),
+        "video/x-m4v" => Some("m4v"),
+        "video/webm" | "audio/webm" => Some("webm"),
+        _ => None,
     }
+}
+
+/// Returns `true` if the last segment of the URL path has a file extension.
+fn has_file_extension(url: &Url) -> bool {
+    Path::new(url.path()).extension().is_some()
+}
+
+/// Number of bytes that are downloaded to determine the media type of a file.
+const SNIFF_SIZE: usize = 8192;
+
+/// Determine the media type of the file at `url` from its first bytes.
+///
+/// This uses a ranged request, so that the full file doesn't need to be downloaded.
+async fn sniff_content_type(
+    client: &Client,
+    host_limiter: &HostLimiter,
+    url: &Url,
+    headers: &HeaderMap,
+) -> Option<String> {
+    let _permit = host_limiter.acquire(url).await;
+    let request = with_headers(client.get(url.as_str()), Some(headers)).header(
+        reqwest::header::RANGE,
+        format!("bytes=0-{}", SNIFF_SIZE - 1),
+    );
+    let mut response = trace::send(request).await.ok()?.error_for_status().ok()?;
+    // Servers may ignore the range, so we stop reading once we have enough data.
+    let mut data = Vec::new();
+    while data.len() < SNIFF_SIZE {
+        match response.chunk().await.ok()? {
+            Some(chunk) => data.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    infer::get(&data).map(|kind| kind.mime_type().to_owned())
+}
+
+/// Fill in the missing `type` attributes of the enclosures that would be downloaded by sniffing
+/// the file contents.
+///
+/// Enclosures are skipped if their URL has a file extension or the episode has already been
+/// downloaded.
+async fn sniff_enclosure_types(
+    context: &AppContext,
+    host_limiter: &HostLimiter,
+    state: &State,
+    podcast: &PodcastConfig,
+    channel: &mut rss::Channel,
+) {
+    let client = context.client_for(podcast.proxy.as_deref());
+    // The headers have been validated when loading the config.
+    let headers = podcast.header_map().unwrap_or_default();
+    let headers = &headers;
+    let episode_limit = episode_limit(&context.config, podcast).unwrap_or(usize::MAX);
+    let enclosures: Vec<_> = channel
+        .items
+        .iter_mut()
+        .filter(|item| item.enclosure.is_some())
+        .take(episode_limit)
+        .filter(|item| {
+            item_guid(item).is_some_and(|guid| !state.is_downloaded(&podcast.feed_url, &guid))
+        })
+        .filter_map(|item| item.enclosure.as_mut())
+        .filter(|enclosure| enclosure.mime_type.trim().is_empty())
+        .filter_map(|enclosure| {
+            let url = Url::parse(&enclosure.url).ok()?;
+            (!has_file_extension(&url)).then_some((url, enclosure))
+        })
+        .collect();
+    futures::stream::iter(enclosures)
+        .for_each_concurrent(None, |(url, enclosure)| async move {
+            if let Some(mime_type) = sniff_content_type(client, host_limiter, &url, headers).await {
+                enclosure.mime_type = mime_type;
+            }
+        })
+        .await;
+}
+
+/// Determine the file names from the `Content-Disposition` headers of the enclosures that would
+/// be downloaded, keyed by the enclosure URL.
+///
+/// Enclosures are skipped if the episode has already been downloaded. Enclosures without a
+/// file name in the header are not included.
+async fn content_disposition_names(
+    context: &AppContext,
+    host_limiter: &HostLimiter,
+    state: &State,
+    podcast: &PodcastConfig,
+    channel: &rss::Channel,
+) -> HashMap<String, String> {
+    let client = context.client_for(podcast.proxy.as_deref());
+    // The headers have been validated when loading the config.
+    let headers = podcast.header_map().unwrap_or_default();
+    let headers = &headers;
+    let episode_limit = episode_limit(&context.config, podcast).unwrap_or(usize::MAX);
+    let enclosure_urls: Vec<_> = channel
+        .items
+        .iter()
+        .filter(|item| item.enclosure.is_some())
+        .take(episode_limit)
+        .filter(|item| {
+            item_guid(item).is_some_and(|guid| !state.is_downloaded(&podcast.feed_url, &guid))
+        })
+        .filter_map(|item| item.enclosure.as_ref())
+        .filter_map(|enclosure| Some((enclosure.url.clone(), Url::parse(&enclosure.url).ok()?)))
+        .collect();
+    futures::stream::iter(enclosure_urls)
+        .map(|(enclosure_url, url)| async move {
+            let _permit = host_limiter.acquire(&url).await;
+            let file_name = check_url(client, &url, Some(headers))
+                .await
+                .ok()?
+                .headers()
+                .get(reqwest::header::CONTENT_DISPOSITION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(content_disposition_file_name)?;
+            Some((enclosure_url, file_name))
+        })
+        .buffer_unordered(usize::MAX)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await
+}
+
+/// Select the transcript to download from the item's `<podcast:transcript>` elements.
+///
+/// If a `preferred_format` (either a file extension like `vtt` or a MIME type like `text/vtt`) is
+/// given and available, that transcript is used. Otherwise, the first transcript is selected.
+fn select_transcript(
+    transcripts: &[rss::extension::Extension],
+    preferred_format: Option<&str>,
+    mime_extensions: Option<&HashMap<String, String>>,
+) -> Option<Sidecar> {
+    let mut candidates: Vec<(bool, Sidecar)> = transcripts
+        .iter()
+        .filter_map(|ext| {
+            let url = reqwest::Url::parse(ext.attrs.get("url")?).ok()?;
+            let mime_type = ext.attrs.get("type").map(String::as_str);
+            let extension = transcript_extension(mime_type, &url, mime_extensions);
+            let is_preferred = preferred_format.is_some_and(|format| {
+                format.eq_ignore_ascii_case(&extension)
+                    || mime_type.is_some_and(|mime_type| format.eq_ignore_ascii_case(mime_type))
+            });
+            Some((
+                is_preferred,
+                Sidecar {
+                    kind: "transcript",
+                    url,
+                    extension,
+                },
+            ))
+        })
+        .collect();
+    let index = candidates
+        .iter()
+        .position(|(is_preferred, _)| *is_preferred)
+        .unwrap_or(0);
+    (index < candidates.len()).then(|| candidates.swap_remove(index).1)
+}
 
+/// Download a small file (e.g. a chapters file) to `path` without displaying any progress.
+pub async fn download_sidecar(
+    client: &Client,
+    host_limiter: &HostLimiter,
+    url: &Url,
+    headers: Option<&HeaderMap>,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _permit = host_limiter.acquire(url).await;
+    let response = trace::send(with_headers(client.get(url.as_str()), headers))
+        .await?
+        .error_for_status()?;
+    let data = response.bytes().await?;
+    std::fs::write(path, &data)?;
     Ok(())
 }
 
+/// Returns the current time as UNIX timestamp.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Returns the interval in which the channel should be refreshed (in seconds).
+///
+/// This is read from the `<ttl>` element or the `sy:updatePeriod`/`sy:updateFrequency` elements.
+fn update_interval(channel: &rss::Channel) -> Option<u64> {
+    if let Some(ttl) = channel
+        .ttl
+        .as_deref()
+        .and_then(|ttl| ttl.trim().parse::<u64>().ok())
+    {
+        // The value comes from the feed, so it might be arbitrarily large.
+        return Some(ttl.saturating_mul(60));
+    }
+
+    channel.syndication_ext.as_ref().map(|ext| {
+        let period = match ext.period {
+            UpdatePeriod::Hourly => 60 * 60,
+            UpdatePeriod::Daily => 24 * 60 * 60,
+            UpdatePeriod::Weekly => 7 * 24 * 60 * 60,
+            UpdatePeriod::Monthly => 30 * 24 * 60 * 60,
+            UpdatePeriod::Yearly => 365 * 24 * 60 * 60,
+        };
+        period / u64::from(ext.frequency.max(1))
+    })
+}
+
+/// Summarize the result of a feed fetch for the feed's history.
+fn fetch_result(
+    result: &Result<rss::Channel, Box<dyn std::error::Error>>,
+    now: u64,
+) -> FetchResult {
+    match result {
+        Ok(channel) => FetchResult {
+            timestamp: now,
+            success: true,
+            status: None,
+            error: None,
+            items: Some(channel.items.len()),
+            latest_pub_date: channel
+                .items
+                .iter()
+                .filter_map(|item| item.pub_date.as_deref().and_then(parse_date))
+                .max(),
+        },
+        Err(err) => FetchResult {
+            timestamp: now,
+            success: false,
+            status: error_status(err.as_ref()).map(|status| status.as_u16()),
+            error: Some(err.to_string()),
+            items: None,
+            latest_pub_date: None,
+        },
+    }
+}
+
+/// Sort the items of a feed by the given key.
+///
+/// The sort is stable, so items with the same key keep their order from the feed.
+fn sort_items(items: &mut [rss::Item], sort_key: SortKey) {
+    match sort_key {
+        SortKey::FeedOrder => (),
+        // Newest first, items without a (valid) date last.
+        SortKey::PubDate => items
+            .sort_by_key(|item| std::cmp::Reverse(item.pub_date.as_deref().and_then(parse_date))),
+        SortKey::Guid => items.sort_by_key(item_guid),
+    }
+}
+
+/// Returns the GUID of the item, falling back to the enclosure URL.
+pub fn item_guid(item: &rss::Item) -> Option<String> {
+    item.guid
+        .as_ref()
+        .map(|guid| guid.value.clone())
+        .or_else(|| item.enclosure.as_ref().map(|enc| enc.url.clone()))
+}
+
+/// Replace each item that offers several enclosures by one item per enclosure, and return the
+/// GUIDs of the added items.
+///
+/// The item keeps its GUID for the enclosure that has been selected by the parser, so that
+/// existing downloads are recognized. The other enclosures get the GUID with a `#<n>` suffix,
+/// where `n` is the position of the enclosure in the item.
+fn expand_enclosures(channel: &mut rss::Channel) -> HashSet<String> {
+    let mut added_guids = HashSet::new();
+    for item in std::mem::take(&mut channel.items) {
+        let enclosures = all_enclosures(&item);
+        let guid = item_guid(&item);
+        let primary_url = item.enclosure.as_ref().map(|enc| enc.url.clone());
+        channel.items.push(item);
+        let (guid, item) = match (guid, channel.items.last()) {
+            (Some(guid), Some(item)) => (guid, item.clone()),
+            _ => continue,
+        };
+        for (i, enclosure) in enclosures.into_iter().enumerate() {
+            if primary_url.as_ref() == Some(&enclosure.url) {
+                continue;
+            }
+            let guid = format!("{}#{}", guid, i + 1);
+            let mut added = item.clone();
+            added.enclosure = Some(enclosure);
+            added.guid = Some(rss::Guid {
+                value: guid.clone(),
+                permalink: false,
+            });
+            added_guids.insert(guid);
+            channel.items.push(added);
+        }
+    }
+    added_guids
+}
+
 pub async fn fetch_sync_info(
-    directory: PathBuf,
-    podcasts: Vec<PodcastConfig>,
-    max_jobs: usize,
-) -> Vec<EpisodeDownload> {
-    println!("Fetching podcast feeds...");
-    let progress = std::sync::Arc::new(Mutex::new(linya::Progress::new()));
-    let progress1 = progress.clone();
-    let task_count = podcasts.len();
-    let results: Vec<Result<(PodcastConfig, rss::Channel), Box<dyn std::error::Error>>> =
-        futures::stream::iter(podcasts.into_iter())
-            .enumerate()
-            .map(move |(i, podcast)| {
-                let prog1 = progress1.clone();
-                async move {
-                    let mut data: Vec<u8> = Vec::new();
-                    let url = reqwest::Url::parse(&podcast.feed_url)?;
-                    download_file(
-                        &mut data,
-                        prog1.clone(),
-                        &url,
-                        None,
-                        format!("({}/{}) {}", i + 1, &task_count, &podcast.feed_url).as_ref(),
+    context: &AppContext,
+    state: &mut State,
+    options: &SyncOptions,
+) -> (Vec<EpisodeDownload>, SkippedEpisodes) {
+    let config = &context.config;
+    // In JSON lines mode, stdout is reserved for the events.
+    let verbose = options.verbose && !options.json_lines;
+    let directory = &config.download_dir;
+    let max_jobs = config.max_parallel_feeds.unwrap_or(10);
+    let now = unix_timestamp();
+    let show_progress = !options.json_lines && !options.summary_only;
+    // If the episode has been downloaded before, only its feed needs to be fetched.
+    let guid_feed_url = options
+        .guid
+        .as_deref()
+        .and_then(|guid| state.find_feed_url(guid));
+    let podcasts: Vec<_> = config
+        .podcast
+        .iter()
+        .filter(|podcast| guid_feed_url.is_none_or(|feed_url| podcast.feed_url == feed_url))
+        .filter(|podcast| {
+            options
+                .feed_url
+                .as_ref()
+                .is_none_or(|feed_url| podcast.feed_url == *feed_url)
+        })
+        .filter(|podcast| {
+            let skip =
+                !(options.ignore_ttl || options.force) && state.is_fresh(&podcast.feed_url, now);
+            if skip && options.json_lines {
+                Event::FeedUpToDate {
+                    feed_url: &podcast.feed_url,
+                }
+                .emit();
+            } else if skip && verbose && show_progress {
+                println!(
+                    "{}",
+                    format!(
+                        "Skipping feed (update interval has not elapsed): {}",
+                        podcast.feed_url
                     )
-                    .await?;
-                    let channel = rss::Channel::read_from(&data[..])?;
-                    Ok((podcast, channel))
+                    .yellow()
+                );
+            }
+            !skip
+        })
+        .filter(|podcast| {
+            let retry_at = match state.backoff_until(&podcast.feed_url) {
+                Some(retry_at) if !options.force && retry_at > now => retry_at,
+                _ => return true,
+            };
+            if options.json_lines {
+                Event::FeedBackedOff {
+                    feed_url: &podcast.feed_url,
+                    retry_at,
                 }
-            })
-            .buffered(max_jobs)
+                .emit();
+            } else if show_progress {
+                println!(
+                    "{}",
+                    format!(
+                        "Skipping failing feed (retrying in {}): {}",
+                        format_cooldown(retry_at - now),
+                        podcast.feed_url
+                    )
+                    .yellow()
+                );
+            }
+            false
+        })
+        .cloned()
+        .collect();
+    let skip_explicit = config.skip_explicit.unwrap_or(false);
+    let redownload_on_update = config.redownload_on_update.unwrap_or(false);
+    let download_chapters = config.download_chapters.unwrap_or(false);
+    let download_transcripts = config.download_transcripts.unwrap_or(false);
+    let save_metadata = config.save_metadata.unwrap_or(false);
+    let mime_extensions = config.mime_extensions.as_ref();
+    let host_limiter = HostLimiter::new(config.max_connections_per_host.unwrap_or(4));
+    let tracking_prefixes: Vec<&str> = if config.strip_tracking.unwrap_or(false) {
+        DEFAULT_TRACKING_PREFIXES
+            .iter()
+            .copied()
+            .chain(
+                config
+                    .tracking_prefixes
+                    .iter()
+                    .flatten()
+                    .map(String::as_str),
+            )
             .collect()
-            .await;
+    } else {
+        Vec::new()
+    };
+    let tracking_prefixes = &tracking_prefixes;
+    let published_after = state
+        .last_run
+        .filter(|_| options.since_last_run)
+        .map(|last_run| last_run as i64);
+    let episode_limit = |podcast: &PodcastConfig| match options.episode_limit {
+        Some(0) => None,
+        Some(limit) => Some(limit),
+        None => episode_limit(config, podcast),
+    };
+
+    if show_progress {
+        eprintln!("Fetching podcast feeds...");
+    }
+    let fetcher = FeedFetcher {
+        multibar: show_progress.then(|| Arc::new(Mutex::new(linya::Progress::new()))),
+        host_limiter: &host_limiter,
+        timeout: Duration::from_secs(config.feed_timeout_secs.unwrap_or(15)),
+        max_pages: config.max_feed_pages.unwrap_or(10),
+        max_size: config.max_feed_size.unwrap_or(DEFAULT_MAX_FEED_SIZE),
+        netrc: context.netrc.as_ref(),
+    };
+    let fetcher = &fetcher;
+    let task_count = podcasts.len();
+    let results: Vec<_> = futures::stream::iter(podcasts)
+        .enumerate()
+        .map(move |(i, podcast)| {
+            async move {
+                let label = format!("({}/{}) {}", i + 1, &task_count, &podcast.feed_url);
+                // The headers have been validated when loading the config.
+                let headers = podcast.header_map().unwrap_or_default();
+                // Looking for a specific episode requires fetching all pages.
+                let episode_limit = match options.guid {
+                    Some(_) => None,
+                    None => episode_limit(&podcast),
+                };
+                let result = fetcher
+                    .fetch(
+                        context.client_for(podcast.proxy.as_deref()),
+                        &podcast.feed_url,
+                        &headers,
+                        &label,
+                        episode_limit,
+                    )
+                    .await;
+                (podcast, result)
+            }
+        })
+        .buffered(max_jobs)
+        .collect()
+        .await;
 
-    results
+    let mut previous_guids = HashMap::new();
+    for (podcast, result) in &results {
+        state.record_fetch_result(&podcast.feed_url, fetch_result(result, now));
+        match result {
+            Ok(channel) => {
+                state.record_fetch(&podcast.feed_url, now, update_interval(channel));
+                let guids = channel.items.iter().filter_map(item_guid).collect();
+                // Episodes that are only resolved are still new for the next sync.
+                let previous = if options.resolve_only {
+                    state
+                        .feeds
+                        .get(&podcast.feed_url)
+                        .and_then(|feed| feed.seen_guids.clone())
+                } else {
+                    state.record_guids(&podcast.feed_url, guids)
+                };
+                previous_guids.insert(podcast.feed_url.clone(), previous);
+                if options.json_lines {
+                    Event::FeedFetched {
+                        feed_url: &podcast.feed_url,
+                        items: channel.items.len(),
+                    }
+                    .emit();
+                }
+            }
+            Err(err) if options.json_lines => Event::FeedFailed {
+                feed_url: &podcast.feed_url,
+                error: err.to_string(),
+            }
+            .emit(),
+            Err(_) => (),
+        }
+    }
+    let state = &*state;
+    let previous_guids = &previous_guids;
+
+    let mut results = results;
+    let mut disposition_names = HashMap::new();
+    // GUIDs of the items that have been added for additional enclosures, keyed by feed URL.
+    let mut added_enclosures: HashMap<String, HashSet<String>> = HashMap::new();
+    for (podcast, result) in &mut results {
+        let channel = match result {
+            Ok(channel) => channel,
+            Err(_) => continue,
+        };
+        // The episode limit applies to the sorted items.
+        sort_items(
+            &mut channel.items,
+            podcast.sort_key.or(config.sort_key).unwrap_or_default(),
+        );
+        // The quality has been validated when loading the config.
+        if let Some(quality) = podcast
+            .quality
+            .as_ref()
+            .or(config.quality.as_ref())
+            .and_then(|quality| quality.parse().ok())
+        {
+            select_enclosure_quality(channel, quality);
+        }
+        if podcast
+            .all_enclosures
+            .or(config.all_enclosures)
+            .unwrap_or(false)
+        {
+            added_enclosures.insert(podcast.feed_url.clone(), expand_enclosures(channel));
+        }
+        let sniff = podcast
+            .sniff_content_type
+            .or(config.sniff_content_type)
+            .unwrap_or(false);
+        if sniff {
+            sniff_enclosure_types(context, &host_limiter, state, podcast, channel).await;
+        }
+        let use_content_disposition = podcast
+            .use_content_disposition
+            .or(config.use_content_disposition)
+            .unwrap_or(false);
+        if use_content_disposition {
+            disposition_names.extend(
+                content_disposition_names(context, &host_limiter, state, podcast, channel).await,
+            );
+        }
+    }
+    let disposition_names = &disposition_names;
+
+    let mut downloads: Vec<_> = results
         .into_iter()
-        .flat_map(|result| {
-            let (podcast, channel) = result.unwrap();
-            let title = podcast.title.unwrap_or(channel.title);
+        .filter_map(|(podcast, result)| match result {
+            Ok(channel) => Some((podcast, channel)),
+            Err(err) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to fetch feed {}: {}", podcast.feed_url, err).red()
+                );
+                None
+            }
+        })
+        .flat_map(|(podcast, channel)| {
+            let feed_index = config
+                .podcast
+                .iter()
+                .position(|other| other.feed_url == podcast.feed_url)
+                .unwrap_or(usize::MAX);
+            // The headers have been validated when loading the config.
+            let headers = podcast.header_map().unwrap_or_default();
+            let throttle = podcast
+                .max_bandwidth_kbps
+                .map(|kbps| Arc::new(Throttle::from_kbps(kbps)));
+            let episode_limit = match options.guid {
+                Some(_) => usize::MAX,
+                None => episode_limit(&podcast).unwrap_or(usize::MAX),
+            };
+            let title = podcast_dir_name(&podcast, &channel.title);
             let mut path = directory.clone();
-            path.push(title);
+            path.push(&title);
+            let feed_url = podcast.feed_url;
+            let skip_explicit = podcast.skip_explicit.unwrap_or(skip_explicit);
+            let redownload_on_update = podcast.redownload_on_update.unwrap_or(redownload_on_update);
+            let match_downloaded_by = podcast
+                .match_downloaded_by
+                .or(config.match_downloaded_by)
+                .unwrap_or_default();
+            let download_chapters = podcast.download_chapters.unwrap_or(download_chapters);
+            let download_transcripts = podcast.download_transcripts.unwrap_or(download_transcripts);
+            let save_metadata = podcast.save_metadata.unwrap_or(save_metadata);
+            let transcript_format = podcast
+                .transcript_format
+                .or_else(|| config.transcript_format.clone());
+            let podcast_prefix =
+                namespace_prefix(&channel.namespaces, PODCAST_NAMESPACE, "podcast");
+            let filename_template = podcast
+                .filename_template
+                .or_else(|| config.filename_template.clone());
+            let subfolder_template = podcast.subfolder_template;
+            let mirrors = podcast.mirrors.unwrap_or_default();
+            let forced_extension = podcast
+                .force_extension
+                .map(|extension| extension.trim_start_matches('.').to_owned());
+            let include_categories = podcast.include_categories;
+            let exclude_categories = podcast.exclude_categories;
+            let added_guids = added_enclosures.remove(&feed_url).unwrap_or_default();
+            let mut used_file_paths = HashSet::new();
+            let channel_explicit = channel
+                .itunes_ext
+                .as_ref()
+                .and_then(|ext| ext.explicit.as_deref())
+                .is_some_and(is_explicit);
 
             channel
                 .items
                 .into_iter()
                 .filter_map(move |item| {
-                    let (url_string, file_size) = match item.enclosure {
+                    if skip_explicit {
+                        let explicit = item
+                            .itunes_ext
+                            .as_ref()
+                            .and_then(|ext| ext.explicit.as_deref())
+                            .map_or(channel_explicit, is_explicit);
+                        if explicit {
+                            if verbose {
+                                println!(
+                                    "{}",
+                                    format!(
+                                        "Skipping explicit episode: {}",
+                                        item.title.as_deref().unwrap_or("(untitled)")
+                                    )
+                                    .yellow()
+                                );
+                            }
+                            return None;
+                        }
+                    }
+                    if (include_categories.is_some() || exclude_categories.is_some())
+                        && !matches_categories(
+                            &item_categories(&item),
+                            include_categories.as_deref(),
+                            exclude_categories.as_deref(),
+                        )
+                    {
+                        if verbose {
+                            println!(
+                                "{}",
+                                format!(
+                                    "Skipping episode (categories don't match): {}",
+                                    item.title.as_deref().unwrap_or("(untitled)")
+                                )
+                                .yellow()
+                            );
+                        }
+                        return None;
+                    }
+                    // Items without (valid) publication date are kept, so that nothing is missed.
+                    if let Some(published_after) = published_after {
+                        if item
+                            .pub_date
+                            .as_deref()
+                            .and_then(parse_date)
+                            .is_some_and(|pub_date| pub_date <= published_after)
+                        {
+                            return None;
+                        }
+                    }
+
+                    let (url_string, file_size) = match &item.enclosure {
                         Some(enc) => (
-                            enc.url,
+                            &enc.url,
                             enc.length.parse().ok().and_then(|length| {
                                 if length > 0 {
                                     Some(length)
@@ -180,29 +1973,784 @@ pub async fn fetch_sync_info(
                         None => return None,
                     };
 
-                    let url = match reqwest::Url::parse(&url_string) {
-                        Ok(x) => x,
+                    let url = match reqwest::Url::parse(url_string) {
+                        Ok(x) => strip_tracking(&x, tracking_prefixes),
                         Err(_) => return None,
                     };
 
-                    let guid = item.guid.map(|x| x.value).unwrap_or_else(|| url_string);
+                    let mut sidecars = Vec::new();
+                    if download_chapters {
+                        if let Some(url) =
+                            find_extensions(&item.extensions, &podcast_prefix, "chapters")
+                                .iter()
+                                .find_map(|ext| ext.attrs.get("url"))
+                                .and_then(|url| reqwest::Url::parse(url).ok())
+                        {
+                            sidecars.push(Sidecar {
+                                kind: "chapters",
+                                url,
+                                extension: String::from(CHAPTERS_EXTENSION),
+                            });
+                        }
+                    }
+                    if download_transcripts {
+                        let transcripts =
+                            find_extensions(&item.extensions, &podcast_prefix, "transcript");
+                        if let Some(sidecar) = select_transcript(
+                            transcripts,
+                            transcript_format.as_deref(),
+                            mime_extensions,
+                        ) {
+                            sidecars.push(sidecar);
+                        }
+                    }
+
+                    // Unwrap is safe here, because the item has an enclosure.
+                    let guid = item_guid(&item).unwrap();
+                    if options.guid.as_ref().is_some_and(|wanted| *wanted != guid) {
+                        return None;
+                    }
+                    // The size that `sync --resolve-only` has determined is more reliable than
+                    // the one from the feed, unless the enclosure has changed since.
+                    let file_size = state
+                        .pending_episode(&feed_url, &guid)
+                        .filter(|pending| pending.url == url.as_str())
+                        .and_then(|pending| pending.file_size)
+                        .or(file_size);
 
-                    let file_name = PathBuf::from(url.path())
-                        .file_name()
-                        .and_then(|x| x.to_str())
-                        .unwrap_or("episode.mp3")
-                        .to_owned();
+                    let inferred_extension = item
+                        .enclosure
+                        .as_ref()
+                        .and_then(|enc| media_extension(&enc.mime_type, mime_extensions));
+                    let url_file_name = match disposition_names.get(url_string) {
+                        Some(file_name) if Path::new(file_name).extension().is_some() => {
+                            file_name.clone()
+                        }
+                        Some(file_name) => match inferred_extension {
+                            Some(extension) => format!("{}.{}", file_name, extension),
+                            None => file_name.clone(),
+                        },
+                        None => match PathBuf::from(url.path())
+                            .file_name()
+                            .and_then(|x| x.to_str())
+                        {
+                            Some(file_name) if has_file_extension(&url) => file_name.to_owned(),
+                            Some(file_name) => match inferred_extension {
+                                Some(extension) => format!("{}.{}", file_name, extension),
+                                None => file_name.to_owned(),
+                            },
+                            None => format!("episode.{}", inferred_extension.unwrap_or("mp3")),
+                        },
+                    };
+                    let file_name = match &filename_template {
+                        Some(template) => {
+                            let (file_stem, extension) = url_file_name
+                                .rsplit_once('.')
+                                .unwrap_or((&url_file_name, "mp3"));
+                            let itunes_ext = item.itunes_ext.as_ref();
+                            let values = TemplateValues {
+                                file_stem,
+                                title: item.title.as_deref(),
+                                season: itunes_ext.and_then(|ext| ext.season.as_deref()),
+                                episode: itunes_ext.and_then(|ext| ext.episode.as_deref()),
+                            };
+                            format!("{}.{}", render_template(template, &values), extension)
+                        }
+                        None => url_file_name,
+                    };
+                    let file_name = match &forced_extension {
+                        Some(extension) => force_extension(&file_name, extension),
+                        None => file_name,
+                    };
+                    let pub_date = item.pub_date.as_deref().and_then(parse_date);
                     let mut file_path = path.clone();
+                    if let Some(subfolder) = subfolder_template.as_deref().zip(pub_date).and_then(
+                        |(template, pub_date)| render_subfolder_template(template, pub_date),
+                    ) {
+                        file_path.push(subfolder);
+                    }
+                    let file_name = make_unique(&file_path, file_name, &mut used_file_paths);
                     file_path.push(file_name);
+                    let duration = item
+                        .itunes_ext
+                        .as_ref()
+                        .and_then(|ext| ext.duration.as_deref())
+                        .and_then(parse_itunes_duration);
+                    let metadata = save_metadata.then(|| EpisodeMeta {
+                        feed_url: feed_url.clone(),
+                        podcast_title: title.clone(),
+                        guid: guid.clone(),
+                        title: item.title.clone(),
+                        pub_date,
+                        url: url.to_string(),
+                        size: None,
+                        duration,
+                        description: item_show_notes(&item).map(str::to_owned),
+                        categories: item
+                            .categories
+                            .iter()
+                            .map(|category| category.name.trim().to_owned())
+                            .filter(|category| !category.is_empty())
+                            .collect(),
+                    });
                     Some(EpisodeDownload {
+                        feed_url: feed_url.clone(),
+                        feed_index,
+                        podcast_title: title.clone(),
+                        headers: {
+                            let mut headers = headers.clone();
+                            add_netrc_credentials(&mut headers, context.netrc.as_ref(), &url);
+                            headers
+                        },
                         guid,
+                        title: item.title.clone(),
+                        pub_date,
+                        duration,
+                        integrity: item_integrity(&item, &podcast_prefix),
+                        mirror_urls: mirrors
+                            .iter()
+                            .filter_map(|mirror| mirror_url(&url, mirror))
+                            .collect(),
                         url,
                         file_size,
                         file_path,
+                        sidecars,
+                        replaces: None,
+                        metadata,
+                        throttle: throttle.clone(),
+                        proxy: podcast.proxy.clone(),
+                        downloaded: false,
                     })
                 })
-                .take(1)
-                .filter(|dl| !dl.file_path.exists())
+                // Additional enclosures belong to the preceding episode, so they are not counted.
+                .scan(0, move |episode_count, dl| {
+                    let added = added_guids.contains(&dl.guid);
+                    if !added {
+                        *episode_count += 1;
+                    }
+                    (*episode_count <= episode_limit).then_some((dl, added))
+                })
+                .filter_map(move |(mut dl, added)| {
+                    if options.guid.is_some() {
+                        // Replace the previously downloaded file, which may have a different name.
+                        dl.replaces = state
+                            .episode(&dl.feed_url, &dl.guid)
+                            .map(|episode| directory.join(&episode.file_path));
+                        return Some(dl);
+                    }
+
+                    if options.only_new {
+                        // Additional enclosures are new if the item they belong to is new.
+                        let guid = match dl.guid.rsplit_once('#') {
+                            Some((guid, _)) if added => guid,
+                            _ => &dl.guid,
+                        };
+                        let is_new = previous_guids
+                            .get(&dl.feed_url)
+                            .and_then(Option::as_ref)
+                            .is_none_or(|guids| !guids.contains(guid));
+                        return is_new.then_some(dl);
+                    }
+
+                    if redownload_on_update {
+                        if let Some(previous) = state.updated_episode(
+                            &dl.feed_url,
+                            &dl.guid,
+                            dl.url.as_str(),
+                            dl.pub_date,
+                        ) {
+                            if verbose {
+                                println!(
+                                    "{}",
+                                    format!("Episode has been updated: {}", dl.file_name())
+                                        .yellow()
+                                );
+                            }
+                            dl.replaces = Some(directory.join(&previous.file_path));
+                            return Some(dl);
+                        }
+                    }
+
+                    let download = options.force_redownload
+                        || (!dl.file_path.exists()
+                            && !state.is_downloaded(&dl.feed_url, &dl.guid)
+                            && !state.matches_downloaded(
+                                &dl.feed_url,
+                                match_downloaded_by,
+                                dl.url.as_str(),
+                                dl.title.as_deref(),
+                                dl.pub_date,
+                            ));
+                    dl.downloaded = !download;
+                    Some(dl)
+                })
         })
-        .collect()
+        .collect();
+
+    let skipped = downloads.iter().filter(|dl| dl.downloaded).fold(
+        SkippedEpisodes::default(),
+        |skipped, dl| SkippedEpisodes {
+            count: skipped.count + 1,
+            size: skipped.size + dl.file_size.unwrap_or(0),
+            ..skipped
+        },
+    );
+    if !options.include_downloaded {
+        downloads.retain(|dl| !dl.downloaded);
+    }
+
+    let skipped = match options.max_episodes_total {
+        Some(max_episodes) => SkippedEpisodes {
+            over_limit: limit_total_downloads(&mut downloads, max_episodes),
+            ..skipped
+        },
+        None => skipped,
+    };
+    (downloads, skipped)
+}
+
+/// Keep at most `max_episodes` of the `downloads` that have not been downloaded yet, preferring
+/// the newest episodes across all feeds (episodes without a date last).
+///
+/// The order of the remaining downloads is not changed, so the sort order of each podcast is
+/// kept. Returns the number of removed downloads.
+fn limit_total_downloads(downloads: &mut Vec<EpisodeDownload>, max_episodes: usize) -> usize {
+    let mut pending: Vec<_> = (0..downloads.len())
+        .filter(|&i| !downloads[i].downloaded)
+        .collect();
+    pending.sort_by_key(|&i| std::cmp::Reverse(downloads[i].pub_date));
+    let removed: HashSet<_> = pending.into_iter().skip(max_episodes).collect();
+
+    let mut index = 0;
+    downloads.retain(|_| {
+        index += 1;
+        !removed.contains(&(index - 1))
+    });
+    removed.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::EpisodeState;
+    use crate::test_server::{test_dir, TestResponse, TestServer};
+    use futures::FutureExt;
+
+    /// Simulate `bytes` being received during the second after `*now` and take a sample.
+    fn sample_after(controller: &ConcurrencyController, now: &mut Instant, bytes: usize) {
+        controller.transferred().fetch_add(bytes, Ordering::Relaxed);
+        *now += Duration::from_secs(1);
+        controller.sample(*now);
+    }
+
+    #[test]
+    fn concurrency_controller_raises_limit_while_throughput_improves() {
+        let controller = ConcurrencyController::new(3);
+        let mut now = controller.inner.lock().unwrap().last_sample;
+        assert_eq!(controller.limit(), 1);
+
+        sample_after(&controller, &mut now, 1000);
+        assert_eq!(controller.limit(), 2);
+        sample_after(&controller, &mut now, 2000);
+        assert_eq!(controller.limit(), 3);
+        // The maximum has been reached.
+        sample_after(&controller, &mut now, 3000);
+        assert_eq!(controller.limit(), 3);
+        assert_eq!(controller.semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn concurrency_controller_reverts_increase_without_gain() {
+        let controller = ConcurrencyController::new(4);
+        let mut now = controller.inner.lock().unwrap().last_sample;
+
+        sample_after(&controller, &mut now, 1000);
+        assert_eq!(controller.limit(), 2);
+        sample_after(&controller, &mut now, 1000);
+        assert_eq!(controller.limit(), 1);
+        assert_eq!(controller.semaphore.available_permits(), 1);
+        // The limit is held for a while before it is raised again.
+        for _ in 0..CONCURRENCY_HOLD_SAMPLES {
+            sample_after(&controller, &mut now, 1000);
+            assert_eq!(controller.limit(), 1);
+        }
+        sample_after(&controller, &mut now, 1000);
+        assert_eq!(controller.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrency_controller_halves_limit_on_failure_while_permits_are_in_use() {
+        let controller = ConcurrencyController::new(4);
+        let mut now = controller.inner.lock().unwrap().last_sample;
+        for bytes in [1000, 2000, 4000] {
+            sample_after(&controller, &mut now, bytes);
+        }
+        assert_eq!(controller.limit(), 4);
+
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(controller.acquire().await);
+        }
+        controller.record_failure();
+        sample_after(&controller, &mut now, 0);
+        assert_eq!(controller.limit(), 2);
+
+        // A waiting download must not receive the permits of the first two finished downloads.
+        let waiting = controller.acquire();
+        futures::pin_mut!(waiting);
+        assert!(waiting.as_mut().now_or_never().is_none());
+        permits.pop();
+        assert!(waiting.as_mut().now_or_never().is_none());
+        permits.pop();
+        assert!(waiting.as_mut().now_or_never().is_none());
+        permits.pop();
+        let permit = waiting.as_mut().now_or_never();
+        assert!(permit.is_some());
+        // The remaining and the new download use up the lowered limit.
+        assert_eq!(controller.semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn concurrency_controller_does_not_raise_limit_before_reduction_takes_effect() {
+        let controller = ConcurrencyController::new(2);
+        let mut now = controller.inner.lock().unwrap().last_sample;
+        sample_after(&controller, &mut now, 1000);
+        let permits: Vec<_> = (0..2)
+            .map(|_| controller.acquire().now_or_never().unwrap())
+            .collect();
+        controller.record_failure();
+        sample_after(&controller, &mut now, 0);
+        assert_eq!(controller.limit(), 1);
+
+        for _ in 0..=CONCURRENCY_HOLD_SAMPLES {
+            sample_after(&controller, &mut now, 1000);
+        }
+        assert_eq!(controller.limit(), 1);
+        drop(permits);
+        assert_eq!(controller.semaphore.available_permits(), 1);
+    }
+
+    /// Parse an RSS feed with the given channel elements and no items.
+    fn channel(elements: &str) -> rss::Channel {
+        let feed = format!(
+            "<rss version=\"2.0\" xmlns:sy=\"http://purl.org/rss/1.0/modules/syndication/\">\
+             <channel><title>Podcast</title>{}</channel></rss>",
+            elements
+        );
+        rss::Channel::read_from(feed.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn update_interval_from_ttl() {
+        assert_eq!(update_interval(&channel("<ttl>90</ttl>")), Some(90 * 60));
+        assert_eq!(update_interval(&channel("<ttl>soon</ttl>")), None);
+        assert_eq!(update_interval(&channel("")), None);
+        assert_eq!(
+            update_interval(&channel("<ttl>999999999999999999</ttl>")),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn update_interval_from_syndication() {
+        assert_eq!(
+            update_interval(&channel(
+                "<sy:updatePeriod>daily</sy:updatePeriod><sy:updateFrequency>4</sy:updateFrequency>"
+            )),
+            Some(6 * 60 * 60)
+        );
+        assert_eq!(
+            update_interval(&channel(
+                "<sy:updatePeriod>hourly</sy:updatePeriod><sy:updateFrequency>0</sy:updateFrequency>"
+            )),
+            Some(60 * 60)
+        );
+        // The TTL takes precedence.
+        assert_eq!(
+            update_interval(&channel(
+                "<ttl>5</ttl><sy:updatePeriod>weekly</sy:updatePeriod>"
+            )),
+            Some(5 * 60)
+        );
+    }
+
+    /// Build a planned download of the podcast at `feed_index` in the config.
+    fn episode(feed_index: usize, guid: &str, pub_date: Option<i64>) -> EpisodeDownload {
+        EpisodeDownload {
+            feed_url: format!("https://example.com/{}.xml", feed_index),
+            feed_index,
+            podcast_title: format!("Podcast {}", feed_index),
+            headers: HeaderMap::new(),
+            guid: guid.to_owned(),
+            title: None,
+            pub_date,
+            duration: None,
+            url: Url::parse(&format!("https://example.com/{}.mp3", guid)).unwrap(),
+            integrity: None,
+            mirror_urls: Vec::new(),
+            file_size: None,
+            file_path: PathBuf::from(format!("{}.mp3", guid)),
+            sidecars: Vec::new(),
+            replaces: None,
+            metadata: None,
+            throttle: None,
+            proxy: None,
+            downloaded: false,
+        }
+    }
+
+    /// Returns the GUIDs of `downloads` in order.
+    fn guids(downloads: &[EpisodeDownload]) -> Vec<&str> {
+        downloads.iter().map(|dl| dl.guid.as_str()).collect()
+    }
+
+    #[test]
+    fn limit_total_downloads_keeps_newest_across_feeds() {
+        // The first podcast is sorted oldest first, the second one newest first.
+        let mut downloads = vec![
+            episode(0, "a1", Some(1)),
+            episode(0, "a2", Some(2)),
+            episode(0, "a3", Some(30)),
+            episode(1, "b20", Some(20)),
+            episode(1, "b10", Some(10)),
+        ];
+        assert_eq!(limit_total_downloads(&mut downloads, 3), 2);
+        assert_eq!(guids(&downloads), ["a3", "b20", "b10"]);
+    }
+
+    #[test]
+    fn limit_total_downloads_ignores_downloaded_episodes() {
+        let mut downloads = vec![
+            episode(0, "undated", None),
+            episode(0, "old", Some(1)),
+            EpisodeDownload {
+                downloaded: true,
+                ..episode(0, "downloaded", Some(100))
+            },
+            episode(1, "new", Some(50)),
+        ];
+        assert_eq!(limit_total_downloads(&mut downloads, 2), 1);
+        assert_eq!(guids(&downloads), ["old", "downloaded", "new"]);
+
+        assert_eq!(limit_total_downloads(&mut downloads, 5), 0);
+        assert_eq!(limit_total_downloads(&mut downloads, 0), 2);
+        assert_eq!(guids(&downloads), ["downloaded"]);
+    }
+
+    /// Feed with two episodes whose enclosures are served by `server`.
+    fn serve_fixture_feed(server: &TestServer) {
+        let item = |guid: &str, day: u32| {
+            format!(
+                "<item><guid>{guid}</guid><title>Episode {guid}</title>\
+                 <pubDate>Mon, {day:02} Jan 2024 00:00:00 GMT</pubDate>\
+                 <enclosure url=\"{url}\" length=\"8\" type=\"audio/mpeg\"/></item>",
+                guid = guid,
+                day = day,
+                url = server.url(&format!("/{}.mp3", guid)),
+            )
+        };
+        let feed = format!(
+            "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Fixture</title>{}{}\
+             </channel></rss>",
+            item("ep1", 1),
+            item("ep2", 2),
+        );
+        server.serve("/feed.xml", TestResponse::ok(feed));
+        server.serve("/ep1.mp3", TestResponse::ok("episode1"));
+        server.serve("/ep2.mp3", TestResponse::ok("episode2"));
+    }
+
+    /// Returns a context that downloads the feed on `server` into `dir`, with the additional
+    /// `podcast_options` in the configuration of the podcast.
+    fn test_context(dir: &Path, server: &TestServer, podcast_options: &str) -> AppContext {
+        let config = Config::from_toml_str(&format!(
+            "download_dir = {:?}\nstate_dir = {:?}\n\n[[podcast]]\nfeed_url = {:?}\n{}",
+            dir.join("downloads"),
+            dir.join("state"),
+            server.url("/feed.xml"),
+            podcast_options,
+        ))
+        .unwrap();
+        AppContext::new(
+            Client::new(),
+            HashMap::new(),
+            dir.join("config.toml"),
+            config,
+        )
+        .unwrap()
+    }
+
+    fn sync_options() -> SyncOptions {
+        SyncOptions {
+            summary_only: true,
+            episode_limit: Some(0),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_sync_info_and_download_file_from_server() {
+        let server = TestServer::start().await;
+        serve_fixture_feed(&server);
+        let context = test_context(&test_dir("download-from-server"), &server, "");
+        let mut state = State::default();
+        let (downloads, skipped) = fetch_sync_info(&context, &mut state, &sync_options()).await;
+        assert_eq!(guids(&downloads), ["ep1", "ep2"]);
+        assert_eq!(skipped.count, 0);
+
+        let host_limiter = HostLimiter::new(1);
+        for dl in &downloads {
+            std::fs::create_dir_all(dl.file_path.parent().unwrap()).unwrap();
+            let mut file = std::fs::File::create(&dl.file_path).unwrap();
+            let request = FileRequest {
+                url: &dl.url,
+                headers: Some(&dl.headers),
+                file_size: dl.file_size,
+                throttle: None,
+                max_size: None,
+                transferred: None,
+            };
+            let progress = ProgressDisplay {
+                multibar: None,
+                label: "",
+                total_bar: None,
+            };
+            download_file(
+                &context.client,
+                &mut file,
+                &host_limiter,
+                &request,
+                false,
+                progress,
+            )
+            .await
+            .unwrap();
+        }
+        let contents: Vec<_> = downloads
+            .iter()
+            .map(|dl| std::fs::read_to_string(&dl.file_path).unwrap())
+            .collect();
+        assert_eq!(contents, ["episode1", "episode2"]);
+        assert!(server.requests().contains(&"GET /ep1.mp3".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn fetch_sync_info_skips_existing_files() {
+        let server = TestServer::start().await;
+        serve_fixture_feed(&server);
+        let context = test_context(&test_dir("skip-existing-files"), &server, "");
+        let (downloads, _) =
+            fetch_sync_info(&context, &mut State::default(), &sync_options()).await;
+        let existing = downloads.iter().find(|dl| dl.guid == "ep1").unwrap();
+        std::fs::create_dir_all(existing.file_path.parent().unwrap()).unwrap();
+        std::fs::write(&existing.file_path, "episode1").unwrap();
+
+        let (downloads, skipped) =
+            fetch_sync_info(&context, &mut State::default(), &sync_options()).await;
+        assert_eq!(guids(&downloads), ["ep2"]);
+        assert_eq!(skipped.count, 1);
+        assert_eq!(skipped.size, 8);
+
+        let options = SyncOptions {
+            force_redownload: true,
+            ..sync_options()
+        };
+        let (downloads, skipped) = fetch_sync_info(&context, &mut State::default(), &options).await;
+        assert_eq!(guids(&downloads), ["ep1", "ep2"]);
+        assert_eq!(skipped.count, 0);
+    }
+
+    /// Returns the error of downloading `path` from `server` with `headers`.
+    async fn download_error(
+        server: &TestServer,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Box<dyn std::error::Error> {
+        let url = Url::parse(&server.url(path)).unwrap();
+        let request = FileRequest {
+            url: &url,
+            headers: Some(headers),
+            file_size: None,
+            throttle: None,
+            max_size: None,
+            transferred: None,
+        };
+        let progress = ProgressDisplay {
+            multibar: None,
+            label: "",
+            total_bar: None,
+        };
+        download_file(
+            &Client::new(),
+            &mut Vec::new(),
+            &HostLimiter::new(1),
+            &request,
+            false,
+            progress,
+        )
+        .await
+        .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn is_retryable_classifies_status_codes() {
+        let server = TestServer::start().await;
+        for (status, retryable) in [
+            (400, false),
+            (401, false),
+            (403, false),
+            (404, false),
+            (410, false),
+            (429, true),
+            (500, true),
+            (502, true),
+            (503, true),
+        ] {
+            let path = format!("/{}", status);
+            server.serve(&path, TestResponse::status(status));
+            let err = download_error(&server, &path, &HeaderMap::new()).await;
+            assert_eq!(
+                error_status(err.as_ref()).map(|status| status.as_u16()),
+                Some(status)
+            );
+            assert_eq!(is_retryable(err.as_ref()), retryable, "HTTP {}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn is_retryable_classifies_error_kinds() {
+        // Nothing listens on the port after the listener has been dropped.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let connect_error = Client::new().get(url).send().await.unwrap_err();
+        assert!(is_retryable(&connect_error));
+
+        let elapsed = tokio::time::timeout(Duration::ZERO, futures::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(is_retryable(&elapsed));
+        assert!(is_retryable(&std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset
+        )));
+
+        let parse_error = rss::Channel::read_from(&b"<html></html>"[..]).unwrap_err();
+        assert!(!is_retryable(&parse_error));
+        assert!(!is_retryable(&std::io::Error::from(
+            std::io::ErrorKind::InvalidData
+        )));
+        assert!(!is_retryable(&BotChallengeError {
+            status: Some(StatusCode::FORBIDDEN)
+        }));
+    }
+
+    #[tokio::test]
+    async fn is_credentials_rejected_only_with_credentials() {
+        let server = TestServer::start().await;
+        let mut credentials = HeaderMap::new();
+        credentials.insert(
+            reqwest::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        for (status, rejected) in [(401, true), (403, true), (404, false), (500, false)] {
+            let path = format!("/{}", status);
+            server.serve(&path, TestResponse::status(status));
+            let url = Url::parse(&server.url(&path)).unwrap();
+            let err = download_error(&server, &path, &credentials).await;
+            assert_eq!(
+                is_credentials_rejected(err.as_ref(), &url, &credentials),
+                rejected,
+                "HTTP {}",
+                status
+            );
+            assert!(!is_credentials_rejected(
+                err.as_ref(),
+                &url,
+                &HeaderMap::new()
+            ));
+        }
+
+        let url = Url::parse(&server.url("/401").replace("http://", "http://user:pass@")).unwrap();
+        let err = download_error(&server, "/401", &HeaderMap::new()).await;
+        assert!(is_credentials_rejected(
+            err.as_ref(),
+            &url,
+            &HeaderMap::new()
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetch_sync_info_forces_extension() {
+        let server = TestServer::start().await;
+        let items: String = [
+            ("query", "/query.mp3?token=1", "audio/mpeg"),
+            ("none", "/none", "audio/mp4"),
+            ("other", "/other.m4a", "audio/mp4"),
+            ("double", "/double.mp3.mp3", "audio/mpeg"),
+        ]
+        .iter()
+        .map(|(guid, path, media_type)| {
+            format!(
+                "<item><guid>{}</guid><enclosure url=\"{}\" length=\"1\" type=\"{}\"/></item>",
+                guid,
+                server.url(path).replace('&', "&amp;"),
+                media_type
+            )
+        })
+        .collect();
+        server.serve(
+            "/feed.xml",
+            TestResponse::ok(format!(
+                "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Fixture</title>{}\
+                 </channel></rss>",
+                items
+            )),
+        );
+        let context = test_context(
+            &test_dir("force-extension"),
+            &server,
+            "force_extension = \".mp3\"\n",
+        );
+        let (downloads, _) =
+            fetch_sync_info(&context, &mut State::default(), &sync_options()).await;
+        let file_names: Vec<_> = downloads.iter().map(EpisodeDownload::file_name).collect();
+        assert_eq!(
+            file_names,
+            ["query.mp3", "none.mp3", "other.mp3", "double.mp3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_sync_info_matches_renamed_guid_by_url() {
+        let server = TestServer::start().await;
+        serve_fixture_feed(&server);
+        // The feed has re-published the first episode with a new GUID.
+        let mut state = State::default();
+        state.mark_downloaded(
+            &server.url("/feed.xml"),
+            "old-ep1",
+            EpisodeState {
+                file_path: PathBuf::from("Fixture/ep1.mp3"),
+                url: Some(server.url("/ep1.mp3")),
+                title: None,
+                pub_date: None,
+                duration: None,
+                sha256: None,
+                removed: false,
+            },
+        );
+
+        let context = test_context(&test_dir("renamed-guid-by-guid"), &server, "");
+        let (downloads, _) = fetch_sync_info(&context, &mut state, &sync_options()).await;
+        assert_eq!(guids(&downloads), ["ep1", "ep2"]);
+
+        let context = test_context(
+            &test_dir("renamed-guid-by-url"),
+            &server,
+            "match_downloaded_by = \"url\"\n",
+        );
+        let (downloads, skipped) = fetch_sync_info(&context, &mut state, &sync_options()).await;
+        assert_eq!(guids(&downloads), ["ep2"]);
+        assert_eq!(skipped.count, 1);
+    }
 }
@@ -0,0 +1,71 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Machine-readable events that are printed during a sync in `--json-lines` mode.
+
+use serde::Serialize;
+use std::path::Path;
+
+/// A significant event during a sync.
+///
+/// Each event is serialized as a single JSON object with an `event` field containing the event
+/// type, e.g. `{"event":"feed_fetched","feed_url":"...","items":12}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A feed has been fetched successfully.
+    FeedFetched {
+        feed_url: &'a str,
+        /// Number of items in the feed.
+        items: usize,
+    },
+    /// A feed could not be fetched.
+    FeedFailed { feed_url: &'a str, error: String },
+    /// A feed has not been fetched, because its announced update interval has not elapsed yet.
+    FeedUpToDate { feed_url: &'a str },
+    /// A feed has not been fetched, because its last fetches failed.
+    FeedBackedOff {
+        feed_url: &'a str,
+        /// Time after which the feed is fetched again (as UNIX timestamp).
+        retry_at: u64,
+    },
+    /// An episode download has started.
+    DownloadStarted {
+        feed_url: &'a str,
+        guid: &'a str,
+        url: &'a str,
+        file_path: &'a Path,
+        /// Expected file size (in bytes).
+        size: Option<usize>,
+    },
+    /// An episode has been downloaded successfully.
+    DownloadCompleted {
+        feed_url: &'a str,
+        guid: &'a str,
+        file_path: &'a Path,
+        /// Size of the downloaded file (in bytes).
+        bytes: u64,
+        /// Time it took to download the episode (in seconds).
+        duration_secs: f64,
+    },
+    /// An episode could not be downloaded.
+    DownloadFailed {
+        feed_url: &'a str,
+        guid: &'a str,
+        file_path: &'a Path,
+        error: String,
+    },
+}
+
+impl Event<'_> {
+    /// Print the event as a single line of JSON to stdout.
+    pub fn emit(&self) {
+        // Unwrap is safe here, because the event only contains serializable values.
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for reading namespaced extension elements from feeds.
+
+use crate::config::Quality;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::Url;
+use rss::extension::{Extension, ExtensionMap};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Namespace URI of the [Podcasting 2.0](https://podcastindex.org/namespace/1.0) extensions.
+pub const PODCAST_NAMESPACE: &str = "https://podcastindex.org/namespace/1.0";
+
+/// Namespace URI of the Atom extensions (used e.g. for `<atom:link>`).
+pub const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+/// Namespace URI of the [Media RSS](https://www.rssboard.org/media-rss) extensions.
+pub const MEDIA_NAMESPACE: &str = "http://search.yahoo.com/mrss/";
+
+/// Returns the prefix that is used for the given `namespace` URI in a channel's `namespaces`.
+///
+/// If the feed does not declare the namespace, `default_prefix` is returned.
+pub fn namespace_prefix(
+    namespaces: &BTreeMap<String, String>,
+    namespace: &str,
+    default_prefix: &str,
+) -> String {
+    namespaces
+        .iter()
+        .find(|(_, uri)| uri.trim_end_matches('/') == namespace.trim_end_matches('/'))
+        .map_or_else(|| default_prefix.to_owned(), |(prefix, _)| prefix.clone())
+}
+
+/// Returns all `<prefix:name>` elements from the given channel or item `extensions`.
+pub fn find_extensions<'a>(
+    extensions: &'a ExtensionMap,
+    prefix: &str,
+    name: &str,
+) -> &'a [Extension] {
+    extensions
+        .get(prefix)
+        .and_then(|elements| elements.get(name))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Parse the value of an `itunes:duration` element (either `HH:MM:SS`, `MM:SS` or plain seconds)
+/// into seconds.
+pub fn parse_itunes_duration(value: &str) -> Option<u64> {
+    value.trim().split(':').try_fold(0u64, |total, part| {
+        let part: u64 = part.parse().ok()?;
+        total.checked_mul(60)?.checked_add(part)
+    })
+}
+
+/// Hash algorithms that are supported for integrity checks.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum HashAlgorithm {
+    /// SHA-256
+    Sha256,
+    /// SHA-384
+    Sha384,
+    /// SHA-512
+    Sha512,
+}
+
+/// Expected hash of an enclosure from a `<podcast:integrity type="sri">` element.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Integrity {
+    /// Algorithm of the hash.
+    pub algorithm: HashAlgorithm,
+    /// The base64-encoded digest.
+    pub digest: String,
+}
+
+/// Parse a [Subresource Integrity] value like `sha384-<base64 digest>`.
+///
+/// If the value contains multiple hashes, the one with the strongest supported algorithm is
+/// returned.
+///
+/// [Subresource Integrity]: https://www.w3.org/TR/SRI/
+fn parse_sri(value: &str) -> Option<Integrity> {
+    value
+        .split_whitespace()
+        .filter_map(|hash| {
+            let (algorithm, digest) = hash.split_once('-')?;
+            let algorithm = match algorithm.to_ascii_lowercase().as_str() {
+                "sha256" => HashAlgorithm::Sha256,
+                "sha384" => HashAlgorithm::Sha384,
+                "sha512" => HashAlgorithm::Sha512,
+                _ => return None,
+            };
+            // Options are separated by `?` and not part of the digest.
+            let digest = digest.split('?').next().unwrap_or_default();
+            Some(Integrity {
+                algorithm,
+                digest: digest.to_owned(),
+            })
+        })
+        .max_by_key(|integrity| integrity.algorithm)
+}
+
+/// Returns the expected hash of the item's enclosure (if the feed provides one).
+///
+/// This looks for `<podcast:integrity>` elements directly inside the item, and inside
+/// `<podcast:alternateEnclosure>` elements whose `<podcast:source>` is the enclosure URL. Only
+/// hashes of type `sri` are supported.
+pub fn item_integrity(item: &rss::Item, prefix: &str) -> Option<Integrity> {
+    let enclosure_url = item.enclosure.as_ref().map(|enc| enc.url.as_str());
+    let alternate_integrities = find_extensions(&item.extensions, prefix, "alternateEnclosure")
+        .iter()
+        .filter(|alternate| {
+            alternate
+                .children
+                .get("source")
+                .into_iter()
+                .flatten()
+                .any(|source| source.attrs.get("uri").map(String::as_str) == enclosure_url)
+        })
+        .flat_map(|alternate| alternate.children.get("integrity").into_iter().flatten());
+    find_extensions(&item.extensions, prefix, "integrity")
+        .iter()
+        .chain(alternate_integrities)
+        .filter(|integrity| integrity.attrs.get("type").map(String::as_str) == Some("sri"))
+        .find_map(|integrity| parse_sri(integrity.attrs.get("value")?))
+}
+
+/// Returns the show notes of the item.
+///
+/// The `<content:encoded>` element usually contains the complete (HTML) notes, while
+/// `<description>` is often only a short summary, so the former is preferred.
+pub fn item_show_notes(item: &rss::Item) -> Option<&str> {
+    item.content
+        .as_deref()
+        .filter(|content| !content.trim().is_empty())
+        .or(item.description.as_deref())
+}
+
+/// Returns the URL of the next page of a paginated feed ([RFC 5005]), i.e. the `href` of the
+/// `<atom:link rel="next">` element.
+///
+/// Relative URLs are resolved against `page_url`.
+///
+/// [RFC 5005]: https://www.rfc-editor.org/rfc/rfc5005
+pub fn next_page_url(channel: &rss::Channel, page_url: &Url) -> Option<Url> {
+    let prefix = namespace_prefix(&channel.namespaces, ATOM_NAMESPACE, "atom");
+    find_extensions(&channel.extensions, &prefix, "link")
+        .iter()
+        .find(|link| link.attrs.get("rel").map(String::as_str) == Some("next"))
+        .and_then(|link| link.attrs.get("href"))
+        .and_then(|href| page_url.join(href).ok())
+}
+
+/// Returns the item's `<media:content>` elements that have a URL, including those inside a
+/// `<media:group>`.
+fn media_contents<'a>(item: &'a rss::Item, prefix: &str) -> Vec<&'a Extension> {
+    let groups = find_extensions(&item.extensions, prefix, "group");
+    find_extensions(&item.extensions, prefix, "content")
+        .iter()
+        .chain(
+            groups
+                .iter()
+                .flat_map(|group| group.children.get("content").into_iter().flatten()),
+        )
+        .filter(|content| content.attrs.contains_key("url"))
+        .collect()
+}
+
+/// Convert a `<media:content>` element into an enclosure.
+fn content_enclosure(content: &Extension) -> Option<rss::Enclosure> {
+    let mut enclosure = rss::Enclosure::default();
+    enclosure.set_url(content.attrs.get("url")?.clone());
+    enclosure.set_length(content.attrs.get("fileSize").cloned().unwrap_or_default());
+    enclosure.set_mime_type(content.attrs.get("type").cloned().unwrap_or_default());
+    Some(enclosure)
+}
+
+/// Returns an enclosure for the item's `<media:content>` element (if any).
+///
+/// `<media:content>` elements inside a `<media:group>` are considered as well. If there are
+/// multiple elements, the one marked with `isDefault="true"` is preferred.
+fn media_content_enclosure(item: &rss::Item, prefix: &str) -> Option<rss::Enclosure> {
+    let contents = media_contents(item, prefix);
+    let content = contents
+        .iter()
+        .find(|content| content.attrs.get("isDefault").map(String::as_str) == Some("true"))
+        .or_else(|| contents.first())?;
+    content_enclosure(content)
+}
+
+/// Key under which [`attach_enclosures`] stores the enclosures of an item in its extensions.
+///
+/// The key is no valid namespace prefix, so that it cannot collide with the extensions of the
+/// feed.
+const ENCLOSURES_KEY: &str = "#enclosures";
+
+/// Returns the `<enclosure>` elements of each item in the feed document `data`.
+fn raw_item_enclosures(data: &[u8]) -> quick_xml::Result<Vec<Vec<Extension>>> {
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut items = Vec::new();
+    let mut current_item: Option<Vec<Extension>> = None;
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(element) if element.name() == b"item" => current_item = Some(Vec::new()),
+            Event::Empty(element) if element.name() == b"item" => items.push(Vec::new()),
+            Event::End(element) if element.name() == b"item" => {
+                items.extend(current_item.take());
+            }
+            Event::Start(element) | Event::Empty(element) if element.name() == b"enclosure" => {
+                if let Some(enclosures) = &mut current_item {
+                    let mut enclosure = Extension::default();
+                    enclosure.set_name("enclosure");
+                    for attribute in element.attributes() {
+                        let attribute = attribute?;
+                        enclosure.attrs.insert(
+                            String::from_utf8_lossy(attribute.key).into_owned(),
+                            attribute.unescape_and_decode_value(&reader)?,
+                        );
+                    }
+                    enclosures.push(enclosure);
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(items)
+}
+
+/// Store all `<enclosure>` elements of the items that have more than one in their extensions,
+/// because the RSS parser only keeps the last one.
+///
+/// `data` is the feed document that `channel` has been parsed from. Nothing is stored if the
+/// items of the document cannot be matched with those of the channel.
+pub fn attach_enclosures(channel: &mut rss::Channel, data: &[u8]) {
+    let item_enclosures = match raw_item_enclosures(data) {
+        Ok(item_enclosures) if item_enclosures.len() == channel.items.len() => item_enclosures,
+        _ => return,
+    };
+    for (item, enclosures) in channel.items.iter_mut().zip(item_enclosures) {
+        if enclosures.len() > 1 {
+            item.extensions
+                .entry(ENCLOSURES_KEY.to_owned())
+                .or_default()
+                .insert(String::from("enclosure"), enclosures);
+        }
+    }
+}
+
+/// Returns all enclosures of the item that have been stored by [`attach_enclosures`] (in
+/// document order), or an empty list if the item has at most one.
+pub fn all_enclosures(item: &rss::Item) -> Vec<rss::Enclosure> {
+    find_extensions(&item.extensions, ENCLOSURES_KEY, "enclosure")
+        .iter()
+        .filter_map(|ext| {
+            let mut enclosure = rss::Enclosure::default();
+            enclosure.set_url(ext.attrs.get("url")?.clone());
+            enclosure.set_length(ext.attrs.get("length").cloned().unwrap_or_default());
+            enclosure.set_mime_type(ext.attrs.get("type").cloned().unwrap_or_default());
+            Some(enclosure)
+        })
+        .collect()
+}
+
+/// Use the `<media:content>` element as enclosure for all items of the channel that don't have an
+/// `<enclosure>` (e.g. in some video podcasts).
+pub fn fill_media_content_enclosures(channel: &mut rss::Channel) {
+    let prefix = namespace_prefix(&channel.namespaces, MEDIA_NAMESPACE, "media");
+    for item in &mut channel.items {
+        if item.enclosure.is_none() {
+            item.enclosure = media_content_enclosure(item, &prefix);
+        }
+    }
+}
+
+/// Replace the enclosure of each item that offers multiple `<media:content>` variants with a
+/// `bitrate` attribute by the variant that matches `quality` best.
+///
+/// Items without such variants keep their enclosure.
+pub fn select_enclosure_quality(channel: &mut rss::Channel, quality: Quality) {
+    let prefix = namespace_prefix(&channel.namespaces, MEDIA_NAMESPACE, "media");
+    for item in &mut channel.items {
+        let variants: Vec<(f64, &Extension)> = media_contents(item, &prefix)
+            .into_iter()
+            .filter_map(|content| {
+                let bitrate = content.attrs.get("bitrate")?.trim().parse().ok()?;
+                Some((bitrate, content))
+            })
+            .collect();
+        if variants.len() < 2 {
+            continue;
+        }
+
+        let distance = |bitrate: f64| match quality {
+            Quality::High => -bitrate,
+            Quality::Low => bitrate,
+            Quality::Bitrate(target) => (bitrate - f64::from(target)).abs(),
+        };
+        let enclosure = variants
+            .iter()
+            .min_by(|(a, _), (b, _)| distance(*a).total_cmp(&distance(*b)))
+            .and_then(|(_, content)| content_enclosure(content));
+        if enclosure.is_some() {
+            item.enclosure = enclosure;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the only item of a feed with the given `elements`.
+    fn item(elements: &str) -> rss::Item {
+        let feed = format!(
+            "<rss version=\"2.0\" xmlns:podcast=\"{}\"><channel><title>Test</title>\
+             <item><enclosure url=\"https://example.com/a.mp3\" length=\"1\" type=\"audio/mpeg\"/>\
+             {}</item></channel></rss>",
+            PODCAST_NAMESPACE, elements
+        );
+        rss::Channel::read_from(feed.as_bytes())
+            .unwrap()
+            .items
+            .remove(0)
+    }
+
+    #[test]
+    fn test_parse_sri() {
+        assert_eq!(
+            parse_sri("sha256-abc="),
+            Some(Integrity {
+                algorithm: HashAlgorithm::Sha256,
+                digest: "abc=".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_sri("sha256-abc sha512-def?opt md5-ghi"),
+            Some(Integrity {
+                algorithm: HashAlgorithm::Sha512,
+                digest: "def".to_owned()
+            })
+        );
+        assert_eq!(parse_sri("md5-abc"), None);
+        assert_eq!(parse_sri("abc"), None);
+    }
+
+    #[test]
+    fn test_item_integrity() {
+        let integrity = item_integrity(
+            &item("<podcast:integrity type=\"sri\" value=\"sha384-abc\"/>"),
+            "podcast",
+        );
+        assert_eq!(
+            integrity.map(|integrity| integrity.algorithm),
+            Some(HashAlgorithm::Sha384)
+        );
+        assert_eq!(
+            item_integrity(
+                &item("<podcast:integrity type=\"pgp-signature\" value=\"abc\"/>"),
+                "podcast"
+            ),
+            None
+        );
+        assert_eq!(item_integrity(&item(""), "podcast"), None);
+    }
+
+    #[test]
+    fn test_item_integrity_of_alternate_enclosure() {
+        let alternate = |uri: &str| {
+            format!(
+                "<podcast:alternateEnclosure type=\"audio/mpeg\">\
+                 <podcast:source uri=\"{}\"/>\
+                 <podcast:integrity type=\"sri\" value=\"sha256-abc\"/>\
+                 </podcast:alternateEnclosure>",
+                uri
+            )
+        };
+        assert!(
+            item_integrity(&item(&alternate("https://example.com/a.mp3")), "podcast").is_some()
+        );
+        // The hash belongs to a different file.
+        assert!(
+            item_integrity(&item(&alternate("https://example.com/b.mp3")), "podcast").is_none()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for detecting dead feeds from their fetch history.
+
+use crate::state::FetchResult;
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+/// Number of consecutive failed fetches after which a feed is reported as failing.
+const FAILING_THRESHOLD: usize = 3;
+
+/// Time for which a failing feed is skipped by syncs after its last failed fetch (in seconds).
+///
+/// The cooldown doubles with every further failure.
+const BACKOFF_BASE_SECS: u64 = 60 * 60;
+
+/// Maximum time for which a failing feed is skipped by syncs (in seconds).
+const BACKOFF_MAX_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Time without new episodes after which a feed is reported as stale (in seconds).
+const STALE_AFTER_SECS: i64 = 90 * 24 * 60 * 60;
+
+/// Overall health of a feed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// The feed has been fetched successfully and published episodes recently.
+    Healthy,
+    /// The last fetches failed.
+    Failing,
+    /// The feed has not published any new episodes for a long time.
+    Stale,
+    /// The feed has not been fetched yet.
+    Unknown,
+}
+
+/// Health of a single feed, derived from its fetch history.
+#[derive(Debug, Serialize)]
+pub struct FeedHealth<'a> {
+    pub feed_url: &'a str,
+    pub status: HealthStatus,
+    /// Number of failed fetches since the last successful one.
+    pub consecutive_failures: usize,
+    /// Time of the last successful fetch (as UNIX timestamp).
+    pub last_success: Option<u64>,
+    /// Publication date of the newest episode (as UNIX timestamp).
+    pub latest_pub_date: Option<i64>,
+    /// Error message of the last failed fetch.
+    pub last_error: Option<&'a str>,
+}
+
+/// Returns the number of failed fetches since the last successful one.
+fn consecutive_failures(history: &VecDeque<FetchResult>) -> usize {
+    history
+        .iter()
+        .rev()
+        .take_while(|result| !result.success)
+        .count()
+}
+
+/// Returns the time until which a sync should skip the feed with the given fetch `history`
+/// (as UNIX timestamp), or `None` if the feed has not failed often enough to back off.
+///
+/// A failing feed is skipped for an hour after its last failed fetch. The cooldown doubles with
+/// every further failure, up to a week.
+pub fn backoff_until(history: &VecDeque<FetchResult>) -> Option<u64> {
+    let extra_failures = consecutive_failures(history).checked_sub(FAILING_THRESHOLD)?;
+    let last_failure = history.back()?.timestamp;
+    let cooldown = 2u64
+        .checked_pow(extra_failures as u32)
+        .and_then(|factor| BACKOFF_BASE_SECS.checked_mul(factor))
+        .map_or(BACKOFF_MAX_SECS, |cooldown| cooldown.min(BACKOFF_MAX_SECS));
+    Some(last_failure + cooldown)
+}
+
+/// Format a number of seconds as rounded-up minutes, hours or days (e.g. `3h`).
+pub fn format_cooldown(secs: u64) -> String {
+    let minutes = secs.div_ceil(60).max(1);
+    match minutes {
+        minutes if minutes >= 24 * 60 => format!("{}d", minutes.div_ceil(24 * 60)),
+        minutes if minutes >= 60 => format!("{}h", minutes.div_ceil(60)),
+        minutes => format!("{}m", minutes),
+    }
+}
+
+/// Determine the health of the feed at `feed_url` from its fetch `history` (oldest first).
+pub fn feed_health<'a>(
+    feed_url: &'a str,
+    history: &'a VecDeque<FetchResult>,
+    now: u64,
+) -> FeedHealth<'a> {
+    let consecutive_failures = consecutive_failures(history);
+    let last_success = history
+        .iter()
+        .rev()
+        .find(|result| result.success)
+        .map(|result| result.timestamp);
+    let latest_pub_date = history
+        .iter()
+        .filter_map(|result| result.latest_pub_date)
+        .max();
+    let last_error = history
+        .iter()
+        .rev()
+        .find_map(|result| result.error.as_deref());
+
+    let is_stale = latest_pub_date.is_some_and(|pub_date| pub_date + STALE_AFTER_SECS < now as i64);
+    let status = if history.is_empty() {
+        HealthStatus::Unknown
+    } else if consecutive_failures >= FAILING_THRESHOLD
+        || (last_success.is_none() && consecutive_failures > 0)
+    {
+        HealthStatus::Failing
+    } else if is_stale {
+        HealthStatus::Stale
+    } else {
+        HealthStatus::Healthy
+    };
+
+    FeedHealth {
+        feed_url,
+        status,
+        consecutive_failures,
+        last_success,
+        latest_pub_date,
+        last_error,
+    }
+}
+
+/// Format a UNIX timestamp as `YYYY-MM-DD`.
+fn format_date(timestamp: i64) -> String {
+    chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+/// Render the health report, either human-readable or as JSON.
+pub fn render_health(feeds: &[FeedHealth<'_>], json: bool) -> String {
+    if json {
+        // Unwrap is safe here, because the report only contains serializable values.
+        return format!("{}\n", serde_json::to_string(feeds).unwrap());
+    }
+
+    let mut output = String::new();
+    for feed in feeds {
+        let (label, details) = match feed.status {
+            HealthStatus::Healthy => (" OK ".green(), None),
+            HealthStatus::Failing => (
+                "FAIL".red(),
+                Some(format!(
+                    "failed {} times in a row: {}",
+                    feed.consecutive_failures,
+                    feed.last_error.unwrap_or("unknown error")
+                )),
+            ),
+            HealthStatus::Stale => (
+                "WARN".yellow(),
+                feed.latest_pub_date
+                    .map(|pub_date| format!("no new episodes since {}", format_date(pub_date))),
+            ),
+            HealthStatus::Unknown => (" -- ".normal(), Some(String::from("not fetched yet"))),
+        };
+        // Writing to a String cannot fail.
+        let _ = write!(output, "[{}] {}", label, feed.feed_url);
+        if let Some(details) = details {
+            let _ = write!(output, " ({})", details);
+        }
+        let _ = writeln!(output);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a fetch history from the results of the fetches (oldest first), one hour apart.
+    fn history(results: &[bool]) -> VecDeque<FetchResult> {
+        results
+            .iter()
+            .enumerate()
+            .map(|(i, &success)| FetchResult {
+                timestamp: 1_000_000 + i as u64 * 3600,
+                success,
+                status: None,
+                error: None,
+                items: None,
+                latest_pub_date: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff_until_below_threshold() {
+        assert_eq!(backoff_until(&history(&[])), None);
+        assert_eq!(backoff_until(&history(&[false, false])), None);
+        assert_eq!(backoff_until(&history(&[false, false, false, true])), None);
+    }
+
+    #[test]
+    fn test_backoff_until_doubles_cooldown() {
+        let failures = history(&[true, false, false, false]);
+        let last_failure = failures.back().unwrap().timestamp;
+        assert_eq!(backoff_until(&failures), Some(last_failure + 3600));
+
+        let failures = history(&[true, false, false, false, false, false]);
+        let last_failure = failures.back().unwrap().timestamp;
+        assert_eq!(backoff_until(&failures), Some(last_failure + 4 * 3600));
+    }
+
+    #[test]
+    fn test_backoff_until_is_capped() {
+        let failures = history(&[false; 12]);
+        let last_failure = failures.back().unwrap().timestamp;
+        assert_eq!(
+            backoff_until(&failures),
+            Some(last_failure + BACKOFF_MAX_SECS)
+        );
+
+        // The cooldown would overflow for very long histories.
+        let failures = history(&[false; 65]);
+        let last_failure = failures.back().unwrap().timestamp;
+        assert_eq!(
+            backoff_until(&failures),
+            Some(last_failure + BACKOFF_MAX_SECS)
+        );
+    }
+
+    #[test]
+    fn test_format_cooldown() {
+        assert_eq!(format_cooldown(0), "1m");
+        assert_eq!(format_cooldown(61), "2m");
+        assert_eq!(format_cooldown(3600), "1h");
+        assert_eq!(format_cooldown(3601), "2h");
+        assert_eq!(format_cooldown(BACKOFF_MAX_SECS), "7d");
+    }
+}
@@ -25,6 +25,29 @@
 
 pub(in crate) mod cli;
 pub(in crate) mod config;
+pub(in crate) mod context;
+pub(in crate) mod discover;
+pub(in crate) mod doctor;
 pub(in crate) mod download;
+pub(in crate) mod events;
+pub(in crate) mod extension;
+pub(in crate) mod health;
+pub(in crate) mod library;
+pub(in crate) mod metadata;
+pub(in crate) mod naming;
+pub(in crate) mod netrc;
+pub(in crate) mod notify;
+pub(in crate) mod opml;
+pub(in crate) mod playlist;
+pub(in crate) mod queue;
+pub(in crate) mod state;
+pub(in crate) mod status;
+pub(in crate) mod tags;
+#[cfg(test)]
+pub(in crate) mod test_server;
+pub(in crate) mod trace;
+pub(in crate) mod tracking;
+pub(in crate) mod units;
+pub(in crate) mod version;
 
 pub use cli::main;
@@ -0,0 +1,624 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for managing the downloaded files in the library.
+
+use crate::download::CHAPTERS_EXTENSION;
+use crate::metadata::METADATA_EXTENSION;
+use crate::state::State;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Returns an error if no files can be created in `dir`.
+///
+/// This creates and removes a small temporary file, because checking the permissions alone does
+/// not detect read-only mounts.
+pub fn ensure_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(".podcatcher-rs-write-test");
+    std::fs::File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+/// Move a file or directory from `from` to `to`.
+///
+/// This uses `rename` where possible and falls back to copy and delete (e.g. when moving across
+/// filesystems).
+pub fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    copy_recursively(from, to)?;
+    if from.is_dir() {
+        std::fs::remove_dir_all(from)
+    } else {
+        std::fs::remove_file(from)
+    }
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    std::fs::create_dir(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Move all podcast directories from `old_dir` to `new_dir` and return the canonical path of
+/// `new_dir`.
+///
+/// Fails before moving anything if `new_dir` is not writable, is located inside `old_dir` or
+/// already contains a podcast directory of the same name.
+pub fn migrate(old_dir: &Path, new_dir: &Path) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(new_dir)?;
+    ensure_writable(new_dir)?;
+    let new_dir = new_dir.canonicalize()?;
+    if new_dir.starts_with(old_dir.canonicalize()?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "New directory must not be inside the current download directory!",
+        ));
+    }
+
+    let mut podcast_dirs = Vec::new();
+    for entry in std::fs::read_dir(old_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let target = new_dir.join(entry.file_name());
+        if target.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Target directory already exists: {:?}", target),
+            ));
+        }
+        podcast_dirs.push((entry.path(), target));
+    }
+
+    if podcast_dirs.is_empty() {
+        return Ok(new_dir);
+    }
+
+    let mut progress = linya::Progress::new();
+    let bar = progress.bar(podcast_dirs.len(), "Moving podcasts");
+    for (source, target) in podcast_dirs {
+        move_path(&source, &target)?;
+        progress.inc_and_draw(&bar, 1);
+    }
+
+    Ok(new_dir)
+}
+
+/// A downloaded file in the library.
+#[derive(Debug)]
+pub struct LibraryFile {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Size of the file in bytes.
+    pub size: usize,
+    /// Last modification time of the file.
+    pub modified: SystemTime,
+}
+
+/// Returns all files inside the podcast directories of `download_dir`.
+///
+/// Symlinks are skipped, so that the returned files are guaranteed to be located inside
+/// `download_dir`.
+pub fn list_files(download_dir: &Path) -> io::Result<Vec<LibraryFile>> {
+    let download_dir = download_dir.canonicalize()?;
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&download_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &mut files)?;
+        }
+    }
+    files.retain(|file| file.path.starts_with(&download_dir));
+    Ok(files)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<LibraryFile>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            files.push(LibraryFile {
+                path: entry.path(),
+                size: metadata.len() as usize,
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Remove all empty directories below `download_dir` (but not `download_dir` itself) and return
+/// their paths.
+///
+/// Directories that only contain hidden files (e.g. `.DS_Store`) are kept, unless `aggressive`
+/// is set, in which case the hidden files are deleted as well. Symlinks are never followed.
+pub fn remove_empty_dirs(download_dir: &Path, aggressive: bool) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in std::fs::read_dir(download_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_if_empty(&entry.path(), aggressive, &mut removed)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove the empty directories inside `dir`, and `dir` itself if it is empty afterwards.
+///
+/// Returns `true` if `dir` has been removed.
+fn remove_if_empty(dir: &Path, aggressive: bool, removed: &mut Vec<PathBuf>) -> io::Result<bool> {
+    let mut is_empty = true;
+    let mut hidden_files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !remove_if_empty(&entry.path(), aggressive, removed)? {
+                is_empty = false;
+            }
+        } else if aggressive
+            && file_type.is_file()
+            && entry.file_name().to_string_lossy().starts_with('.')
+        {
+            hidden_files.push(entry.path());
+        } else {
+            is_empty = false;
+        }
+    }
+
+    if !is_empty {
+        return Ok(false);
+    }
+    for file in hidden_files {
+        std::fs::remove_file(file)?;
+    }
+    std::fs::remove_dir(dir)?;
+    removed.push(dir.to_owned());
+    Ok(true)
+}
+
+/// Returns all files in the library that have not been modified for longer than `max_age`.
+pub fn find_expired_files(download_dir: &Path, max_age: Duration) -> io::Result<Vec<LibraryFile>> {
+    let now = SystemTime::now();
+    let mut files = list_files(download_dir)?;
+    files.retain(|file| {
+        now.duration_since(file.modified)
+            .is_ok_and(|age| age > max_age)
+    });
+    Ok(files)
+}
+
+/// A downloaded episode that can be deleted to enforce the library size limit.
+#[derive(Debug)]
+pub struct EvictionCandidate {
+    /// Feed URL of the podcast that the episode belongs to.
+    pub feed_url: String,
+    /// GUID of the episode.
+    pub guid: String,
+    /// The downloaded file.
+    pub file: LibraryFile,
+}
+
+/// Select the episodes that need to be deleted to shrink the library to at most `max_size`.
+///
+/// The oldest episodes (by modification time) are selected first, but the most recent
+/// `keep_at_least(feed_url)` episodes of each podcast are never selected.
+pub fn select_evictions(
+    total_size: usize,
+    max_size: usize,
+    mut candidates: Vec<EvictionCandidate>,
+    keep_at_least: impl Fn(&str) -> usize,
+) -> Vec<EvictionCandidate> {
+    // Sort the newest episodes first, so that the protected episodes of each podcast are skipped.
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.file.modified));
+    let mut kept_counts: HashMap<String, usize> = HashMap::new();
+    candidates.retain(|candidate| {
+        let kept_count = kept_counts.entry(candidate.feed_url.clone()).or_default();
+        if *kept_count < keep_at_least(&candidate.feed_url) {
+            *kept_count += 1;
+            false
+        } else {
+            true
+        }
+    });
+
+    let mut size = total_size;
+    let mut evictions = Vec::new();
+    for candidate in candidates.into_iter().rev() {
+        if size <= max_size {
+            break;
+        }
+        size = size.saturating_sub(candidate.file.size);
+        evictions.push(candidate);
+    }
+    evictions
+}
+
+/// Returns the downloaded episodes that need to be deleted so that the size of all files in the
+/// library does not exceed `max_size`.
+///
+/// Only episodes that are recorded in the `state` are considered, but all files count towards
+/// the library size.
+pub fn find_evictions(
+    download_dir: &Path,
+    state: &State,
+    max_size: usize,
+    keep_at_least: impl Fn(&str) -> usize,
+) -> io::Result<Vec<EvictionCandidate>> {
+    let files = list_files(download_dir)?;
+    let total_size = files.iter().map(|file| file.size).sum();
+    if total_size <= max_size {
+        return Ok(Vec::new());
+    }
+
+    let download_dir = download_dir.canonicalize()?;
+    let mut episodes: HashMap<&Path, (&str, &str)> = HashMap::new();
+    for (feed_url, feed) in &state.feeds {
+        for (guid, episode) in &feed.episodes {
+            if !episode.removed {
+                episodes.insert(&episode.file_path, (feed_url, guid));
+            }
+        }
+    }
+
+    let candidates = files
+        .into_iter()
+        .filter_map(|file| {
+            let relative_path = file.path.strip_prefix(&download_dir).ok()?;
+            let (feed_url, guid) = episodes.get(relative_path)?;
+            Some(EvictionCandidate {
+                feed_url: feed_url.to_string(),
+                guid: guid.to_string(),
+                file,
+            })
+        })
+        .collect();
+    Ok(select_evictions(
+        total_size,
+        max_size,
+        candidates,
+        keep_at_least,
+    ))
+}
+
+/// Extensions of the additional files of an episode, which replace the extension of the episode
+/// file.
+///
+/// Transcripts are saved with the extension of their MIME type or URL, so only the common ones
+/// are listed.
+const SIDECAR_EXTENSIONS: &[&str] = &[
+    METADATA_EXTENSION,
+    CHAPTERS_EXTENSION,
+    "vtt",
+    "srt",
+    "json",
+    "html",
+    "txt",
+];
+
+/// Returns the stem of the episode file that the file at `path` belongs to, if its name ends
+/// with one of the [`SIDECAR_EXTENSIONS`].
+fn sidecar_stem(path: &Path) -> Option<&OsStr> {
+    let name = path.file_name()?.to_str()?;
+    SIDECAR_EXTENSIONS.iter().find_map(|extension| {
+        let stem = name.strip_suffix(extension)?.strip_suffix('.')?;
+        (!stem.is_empty()).then(|| OsStr::new(stem))
+    })
+}
+
+/// Returns the additional files (e.g. chapters) of the episode file at `path` that exist.
+pub fn existing_sidecars(path: &Path) -> Vec<PathBuf> {
+    SIDECAR_EXTENSIONS
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .filter(|sidecar| sidecar != path && sidecar.is_file())
+        .collect()
+}
+
+/// A file in the library whose contents are identical to other files.
+#[derive(Debug)]
+pub struct DuplicateFiles {
+    /// Path of the file that is kept.
+    pub keep: PathBuf,
+    /// The files with the same contents and their additional files (e.g. chapters), which can be
+    /// deleted.
+    pub duplicates: Vec<LibraryFile>,
+}
+
+/// Returns the SHA-256 hash of the file at `path`.
+fn file_sha256(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Find the files in the library that have the same contents as another file.
+///
+/// Files of the same size are compared by their SHA-256 hash. Additional files of an episode
+/// (see [`SIDECAR_EXTENSIONS`], e.g. `episode.chapters.json` next to `episode.mp3`) are not
+/// compared themselves, but are deleted together with the file they belong to.
+///
+/// Of each group of identical files, the one that the `state` records as downloaded is kept.
+/// Otherwise, the file with the most additional files and then the one with the shortest name
+/// (e.g. without the suffix from [`make_unique`](crate::naming::make_unique)) is preferred.
+pub fn find_duplicates(download_dir: &Path, state: &State) -> io::Result<Vec<DuplicateFiles>> {
+    let files = list_files(download_dir)?;
+    let download_dir = download_dir.canonicalize()?;
+    let recorded: HashSet<PathBuf> = state
+        .feeds
+        .values()
+        .flat_map(|feed| feed.episodes.values())
+        .filter(|episode| !episode.removed)
+        .map(|episode| download_dir.join(&episode.file_path))
+        .collect();
+
+    // The largest episode file for each file stem, keyed by directory and stem.
+    let mut largest: HashMap<(&Path, &OsStr), usize> = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        let (Some(dir), Some(stem)) = (file.path.parent(), file.path.file_stem()) else {
+            continue;
+        };
+        if sidecar_stem(&file.path).is_some() {
+            continue;
+        }
+        let index = largest.entry((dir, stem)).or_insert(i);
+        if files[*index].size < file.size {
+            *index = i;
+        }
+    }
+
+    // Additional files belong to the episode file with the same stem (e.g.
+    // `episode.chapters.json` and `episode.json` belong to `episode.mp3`).
+    let mut attached: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut episodes = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let owner = file
+            .path
+            .parent()
+            .zip(sidecar_stem(&file.path))
+            .and_then(|(dir, stem)| largest.get(&(dir, stem)));
+        match owner {
+            Some(&owner) => attached.entry(owner).or_default().push(i),
+            None => episodes.push(i),
+        }
+    }
+
+    let mut by_size: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in episodes {
+        by_size.entry(files[i].size).or_default().push(i);
+    }
+    let mut groups = Vec::new();
+    for candidates in by_size
+        .into_values()
+        .filter(|candidates| candidates.len() > 1)
+    {
+        let mut by_hash: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+        for i in candidates {
+            by_hash
+                .entry(file_sha256(&files[i].path)?)
+                .or_default()
+                .push(i);
+        }
+        groups.extend(by_hash.into_values().filter(|group| group.len() > 1));
+    }
+
+    let mut duplicates = Vec::new();
+    for group in &mut groups {
+        group.sort_by_key(|&i| {
+            let path = &files[i].path;
+            (
+                !recorded.contains(path),
+                std::cmp::Reverse(attached.get(&i).map_or(0, Vec::len)),
+                path.file_name().map_or(0, |name| name.len()),
+                path.clone(),
+            )
+        });
+    }
+    // The files are moved out of the list as they are assigned to a group.
+    let mut files: Vec<_> = files.into_iter().map(Some).collect();
+    for group in groups {
+        let keep = match &files[group[0]] {
+            Some(file) => file.path.clone(),
+            None => continue,
+        };
+        let removed = group[1..]
+            .iter()
+            .flat_map(|i| std::iter::once(i).chain(attached.get(i).into_iter().flatten()))
+            .filter_map(|&i| files[i].take())
+            .collect();
+        duplicates.push(DuplicateFiles {
+            keep,
+            duplicates: removed,
+        });
+    }
+    duplicates.sort_by(|a, b| a.keep.cmp(&b.keep));
+    Ok(duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty download directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "podcatcher-rs-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Podcast")).unwrap();
+        dir
+    }
+
+    /// Returns the file names of the duplicates, sorted.
+    fn duplicate_names(duplicates: &[DuplicateFiles]) -> Vec<String> {
+        let mut names: Vec<_> = duplicates
+            .iter()
+            .flat_map(|group| &group.duplicates)
+            .map(|file| {
+                file.path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn existing_sidecars_of_episode() {
+        let dir = test_dir("existing-sidecars");
+        std::fs::write(dir.join("Podcast/talk.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Podcast/talk.mp4"), "video").unwrap();
+        std::fs::write(dir.join("Podcast/talk.chapters.json"), "{}").unwrap();
+        std::fs::write(dir.join("Podcast/talk.srt"), "").unwrap();
+
+        let mut sidecars = existing_sidecars(&dir.join("Podcast/talk.mp3"));
+        sidecars.sort();
+        assert_eq!(
+            sidecars,
+            [
+                dir.join("Podcast/talk.chapters.json"),
+                dir.join("Podcast/talk.srt")
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates_keeps_one_of_identical_files() {
+        let dir = test_dir("identical");
+        std::fs::write(dir.join("Podcast/episode.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Podcast/episode-2.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Podcast/other.mp3"), "other").unwrap();
+
+        let duplicates = find_duplicates(&dir, &State::default()).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates[0].keep.file_name().unwrap(),
+            OsStr::new("episode.mp3")
+        );
+        assert_eq!(duplicate_names(&duplicates), ["episode-2.mp3"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates_removes_sidecars_with_duplicate() {
+        let dir = test_dir("sidecars");
+        std::fs::write(dir.join("Podcast/a.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Podcast/a.chapters.json"), "{}").unwrap();
+        std::fs::write(dir.join("Podcast/b.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Podcast/b.chapters.json"), "{}").unwrap();
+        std::fs::write(dir.join("Podcast/b.meta.json"), "{}").unwrap();
+
+        // The file with more additional files is kept, and identical additional files of
+        // different episodes are not compared.
+        let duplicates = find_duplicates(&dir, &State::default()).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].keep.file_name().unwrap(), OsStr::new("b.mp3"));
+        assert_eq!(duplicate_names(&duplicates), ["a.chapters.json", "a.mp3"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates_keeps_other_media_with_same_stem() {
+        let dir = test_dir("same-stem");
+        std::fs::create_dir_all(dir.join("Copy")).unwrap();
+        std::fs::write(dir.join("Podcast/talk.mp4"), "video video").unwrap();
+        std::fs::write(dir.join("Podcast/talk.mp3"), "audio").unwrap();
+        std::fs::write(dir.join("Copy/talk.mp4"), "video video").unwrap();
+
+        let duplicates = find_duplicates(&dir, &State::default()).unwrap();
+        assert_eq!(duplicate_names(&duplicates), ["talk.mp4"]);
+        assert!(duplicates[0].duplicates.iter().all(|file| file.size > 5));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_moves_podcast_directories() {
+        let dir = test_dir("migrate");
+        let old_dir = dir.join("old");
+        std::fs::create_dir_all(old_dir.join("Podcast")).unwrap();
+        std::fs::write(old_dir.join("Podcast/episode.mp3"), "audio").unwrap();
+
+        let new_dir = migrate(&old_dir, &dir.join("new/../new")).unwrap();
+        assert_eq!(new_dir, dir.join("new").canonicalize().unwrap());
+        assert_eq!(
+            std::fs::read_to_string(new_dir.join("Podcast/episode.mp3")).unwrap(),
+            "audio"
+        );
+        assert!(!old_dir.join("Podcast").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_fails_before_moving_anything() {
+        let dir = test_dir("migrate-conflict");
+        let old_dir = dir.join("old");
+        std::fs::create_dir_all(old_dir.join("Podcast")).unwrap();
+        std::fs::create_dir_all(old_dir.join("Other")).unwrap();
+        std::fs::create_dir_all(dir.join("new/Podcast")).unwrap();
+
+        let err = migrate(&old_dir, &dir.join("new")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(old_dir.join("Other").exists());
+        assert!(!dir.join("new/Other").exists());
+
+        let err = migrate(&old_dir, &old_dir.join("Other/new")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(old_dir.join("Podcast").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find_expired_files_by_modification_time() {
+        let dir = test_dir("expired");
+        std::fs::create_dir_all(dir.join("Podcast/2021")).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (name, age) in [("old.mp3", 40), ("2021/older.mp3", 400), ("new.mp3", 2)] {
+            let path = dir.join("Podcast").join(name);
+            let file = std::fs::File::create(path).unwrap();
+            file.set_modified(SystemTime::now() - day * age).unwrap();
+        }
+        // Files outside of the podcast directories are ignored.
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let mut names: Vec<_> = find_expired_files(&dir, day * 30)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path.file_name().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["old.mp3", "older.mp3"]);
+        assert!(find_expired_files(&dir, day * 1000).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for writing the metadata of downloaded episodes to JSON files.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Extension of the metadata file, which replaces the extension of the episode file.
+pub const METADATA_EXTENSION: &str = "meta.json";
+
+/// Metadata of an episode from the feed, which is written next to the downloaded file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EpisodeMeta {
+    pub feed_url: String,
+    /// Title of the podcast.
+    pub podcast_title: String,
+    pub guid: String,
+    pub title: Option<String>,
+    /// Publication date (as UNIX timestamp).
+    pub pub_date: Option<i64>,
+    /// Enclosure URL of the episode.
+    pub url: String,
+    /// Size of the downloaded file (in bytes).
+    pub size: Option<u64>,
+    /// Duration of the episode (in seconds).
+    pub duration: Option<u64>,
+    /// Show notes of the episode.
+    pub description: Option<String>,
+    pub categories: Vec<String>,
+}
+
+impl EpisodeMeta {
+    /// Write the metadata as JSON next to the episode file at `episode_path`.
+    pub fn save(&self, episode_path: &Path) -> std::io::Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(episode_path.with_extension(METADATA_EXTENSION), content)
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for deriving the file names of downloaded episodes.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Values that can be used in a file name template.
+#[derive(Debug, Default)]
+pub struct TemplateValues<'a> {
+    /// File name from the enclosure URL (without extension).
+    pub file_stem: &'a str,
+    /// Episode title.
+    pub title: Option<&'a str>,
+    /// Season number (`itunes:season`).
+    pub season: Option<&'a str>,
+    /// Episode number (`itunes:episode`).
+    pub episode: Option<&'a str>,
+}
+
+/// Convert `text` into a lowercase, ASCII-only string that is safe to use in file names.
+///
+/// Accented characters are replaced by their base characters, and all other non-alphanumeric
+/// characters are collapsed into a single `-`.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.nfkd() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if c.is_ascii() && !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let len = slug.trim_end_matches('-').len();
+    slug.truncate(len);
+    slug
+}
+
+/// Returns a directory name for a podcast that has no title, derived from the host of its feed.
+///
+/// For example, `https://feeds.example.com/show.xml` results in `feeds-example-com`.
+pub fn feed_url_slug(feed_url: &str) -> String {
+    let slug = reqwest::Url::parse(feed_url)
+        .ok()
+        .and_then(|url| url.host_str().map(slugify))
+        .unwrap_or_default();
+    if slug.is_empty() {
+        String::from("untitled-podcast")
+    } else {
+        slug
+    }
+}
+
+/// Format a season or episode number with at least two digits (e.g. `5` becomes `05`).
+fn format_number(number: Option<&str>) -> String {
+    match number.map(str::trim) {
+        Some(number) => match number.parse::<u32>() {
+            Ok(number) => format!("{:02}", number),
+            Err(_) => slugify(number),
+        },
+        None => String::new(),
+    }
+}
+
+/// Render a file name template (without extension).
+///
+/// The following placeholders are supported:
+///
+/// - `{filename}`: File name from the enclosure URL (without extension).
+/// - `{slug}`: URL-safe version of the episode title.
+/// - `{season}`: Season number, padded to two digits.
+/// - `{episode}`: Episode number, padded to two digits.
+///
+/// For example, `s{season}e{episode}-{slug}` results in `s02e05-interview-with-x`.
+pub fn render_template(template: &str, values: &TemplateValues<'_>) -> String {
+    let slug = values
+        .title
+        .map(slugify)
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| slugify(values.file_stem));
+    let rendered = template
+        .replace("{filename}", values.file_stem)
+        .replace("{slug}", &slug)
+        .replace("{season}", &format_number(values.season))
+        .replace("{episode}", &format_number(values.episode));
+
+    // The result must not contain any directory separators.
+    rendered.replace(['/', '\\'], "-")
+}
+
+/// Render a subfolder template for an episode published at `pub_date` (UNIX timestamp).
+///
+/// The placeholders `{year}`, `{month}` and `{day}` are supported, and `/` separates nested
+/// directories (e.g. `{year}/{month}` results in `2022/05`). Returns `None` if the date is
+/// invalid or the template does not contain any directory name.
+pub fn render_subfolder_template(template: &str, pub_date: i64) -> Option<PathBuf> {
+    let date = chrono::NaiveDateTime::from_timestamp_opt(pub_date, 0)?;
+    let rendered = template
+        .replace("{year}", &date.format("%Y").to_string())
+        .replace("{month}", &date.format("%m").to_string())
+        .replace("{day}", &date.format("%d").to_string());
+
+    // Empty components and references to parent directories are skipped, so that the result
+    // is always located inside the podcast directory.
+    let path: PathBuf = rendered
+        .split(['/', '\\'])
+        .map(str::trim)
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect();
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Split the parameters of a header value at `;`, ignoring separators in quoted strings.
+fn split_header_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+/// Decode an extended parameter value as defined in RFC 5987 (e.g. `UTF-8''file%20name.mp3`).
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut chars = encoded.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Returns the file name from a `Content-Disposition` header value (if any).
+///
+/// The extended `filename*` parameter is preferred over `filename`. The result is sanitized with
+/// [`sanitize_file_name`].
+pub fn content_disposition_file_name(value: &str) -> Option<String> {
+    let mut file_name = None;
+    let mut ext_file_name = None;
+    for param in split_header_params(value).into_iter().skip(1) {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("filename*") {
+            ext_file_name = decode_ext_value(value.trim_matches('"'));
+        } else if name.eq_ignore_ascii_case("filename") {
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => value.to_owned(),
+            };
+            file_name = Some(value);
+        }
+    }
+    ext_file_name
+        .or(file_name)
+        .and_then(|file_name| sanitize_file_name(&file_name))
+}
+
+/// Make a file name from an untrusted source (e.g. a server) safe to use.
+///
+/// Directory components are removed, and control characters and characters that are not allowed
+/// on common filesystems are replaced by `_`. Returns `None` if nothing usable is left.
+pub fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let file_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let file_name: String = file_name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Leading dots would create hidden files, and trailing dots and spaces are not allowed on
+    // Windows.
+    let file_name = file_name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' ']);
+    (!file_name.is_empty()).then(|| file_name.to_owned())
+}
+
+/// Returns `file_name` with its extension replaced by `extension` (given without leading dot).
+///
+/// Only a short alphanumeric suffix counts as extension, so that e.g. `Episode 1.5 Interview`
+/// becomes `Episode 1.5 Interview.mp3`. Repeated extensions (e.g. `episode.mp3.mp3`) are
+/// collapsed.
+pub fn force_extension(file_name: &str, extension: &str) -> String {
+    let is_extension = |suffix: &str| {
+        (1..=5).contains(&suffix.len()) && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    let mut stem = match file_name.rsplit_once('.') {
+        Some((rest, suffix)) if !rest.is_empty() && is_extension(suffix) => rest,
+        _ => file_name,
+    };
+    while let Some((rest, suffix)) = stem.rsplit_once('.') {
+        if rest.is_empty() || !suffix.eq_ignore_ascii_case(extension) {
+            break;
+        }
+        stem = rest;
+    }
+    format!("{}.{}", stem, extension)
+}
+
+/// Returns a file name that has not been used yet in the directory `dir` by appending a numeric
+/// suffix if necessary.
+///
+/// The path of the returned name in `dir` is added to `used_paths`.
+pub fn make_unique(dir: &Path, file_name: String, used_paths: &mut HashSet<PathBuf>) -> String {
+    if used_paths.insert(dir.join(&file_name)) {
+        return file_name;
+    }
+
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (file_name.as_str(), String::new()),
+    };
+    (2..)
+        .map(|i| format!("{}-{}{}", stem, i, extension))
+        .find(|candidate| used_paths.insert(dir.join(candidate)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_unique_appends_suffix() {
+        let dir = Path::new("Podcast");
+        let mut used_paths = HashSet::new();
+        let names: Vec<_> = [
+            "episode.mp3",
+            "episode.mp3",
+            "episode.mp3",
+            "notes",
+            "notes",
+        ]
+        .into_iter()
+        .map(|name| make_unique(dir, name.to_owned(), &mut used_paths))
+        .collect();
+        assert_eq!(
+            names,
+            [
+                "episode.mp3",
+                "episode-2.mp3",
+                "episode-3.mp3",
+                "notes",
+                "notes-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_make_unique_skips_used_suffixes() {
+        let dir = Path::new("Podcast");
+        let mut used_paths = HashSet::from([dir.join("episode.mp3"), dir.join("episode-2.mp3")]);
+        assert_eq!(
+            make_unique(dir, String::from("episode.mp3"), &mut used_paths),
+            "episode-3.mp3"
+        );
+    }
+
+    #[test]
+    fn test_make_unique_per_directory() {
+        let mut used_paths = HashSet::new();
+        let first = make_unique(
+            Path::new("Podcast/2021"),
+            String::from("episode.mp3"),
+            &mut used_paths,
+        );
+        let second = make_unique(
+            Path::new("Podcast/2022"),
+            String::from("episode.mp3"),
+            &mut used_paths,
+        );
+        assert_eq!(
+            (first.as_str(), second.as_str()),
+            ("episode.mp3", "episode.mp3")
+        );
+    }
+
+    #[test]
+    fn test_render_subfolder_template() {
+        // 2022-05-03T12:00:00Z
+        let pub_date = 1_651_579_200;
+        assert_eq!(
+            render_subfolder_template("{year}/{month}", pub_date),
+            Some(PathBuf::from("2022/05"))
+        );
+        assert_eq!(
+            render_subfolder_template("{year}-{month}-{day}", pub_date),
+            Some(PathBuf::from("2022-05-03"))
+        );
+        assert_eq!(
+            render_subfolder_template("../{year}//./", pub_date),
+            Some(PathBuf::from("2022"))
+        );
+        assert_eq!(render_subfolder_template("/../", pub_date), None);
+    }
+
+    #[test]
+    fn test_slugify_ascii() {
+        assert_eq!(slugify("Ep. 1: Hello, World!"), "ep-1-hello-world");
+        assert_eq!(
+            slugify("  --Leading and trailing--  "),
+            "leading-and-trailing"
+        );
+        assert_eq!(slugify("?!"), "");
+    }
+
+    #[test]
+    fn test_slugify_unicode() {
+        assert_eq!(slugify("Café Crème"), "cafe-creme");
+        assert_eq!(slugify("Ｆｕｌｌ\u{3000}Ｗｉｄｔｈ"), "full-width");
+        // Characters without an ASCII base character are dropped.
+        assert_eq!(slugify("日本語 Podcast"), "podcast");
+        assert_eq!(slugify("Podcast 🎙 Talk"), "podcast-talk");
+    }
+
+    #[test]
+    fn test_content_disposition_file_name() {
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=episode.mp3").as_deref(),
+            Some("episode.mp3")
+        );
+        assert_eq!(
+            content_disposition_file_name(r#"attachment; filename="Episode \"1\"; Part 2.mp3""#)
+                .as_deref(),
+            Some("Episode _1_; Part 2.mp3")
+        );
+        assert_eq!(content_disposition_file_name("attachment"), None);
+        assert_eq!(content_disposition_file_name("inline; filename=\"\""), None);
+    }
+
+    #[test]
+    fn test_content_disposition_file_name_ext_value() {
+        assert_eq!(
+            content_disposition_file_name(
+                "attachment; filename*=UTF-8''%C3%84rger%20%C3%BCber.mp3; filename=fallback.mp3"
+            )
+            .as_deref(),
+            Some("Ärger über.mp3")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; FILENAME*=iso-8859-1'de'Caf%E9.mp3")
+                .as_deref(),
+            Some("Café.mp3")
+        );
+        // Undecodable extended values fall back to `filename`.
+        assert_eq!(
+            content_disposition_file_name(
+                "attachment; filename*=UTF-16''%FF.mp3; filename=fallback.mp3"
+            )
+            .as_deref(),
+            Some("fallback.mp3")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename*=UTF-8''..%2F..%2Fetc%2Fpasswd")
+                .as_deref(),
+            Some("passwd")
+        );
+    }
+
+    #[test]
+    fn test_force_extension() {
+        assert_eq!(force_extension("episode.m4a", "mp3"), "episode.mp3");
+        assert_eq!(force_extension("episode", "mp3"), "episode.mp3");
+        assert_eq!(force_extension("episode.mp3.mp3", "mp3"), "episode.mp3");
+        assert_eq!(force_extension("episode.MP3.m4a", "mp3"), "episode.mp3");
+        assert_eq!(
+            force_extension("Episode 1.5 Interview", "mp3"),
+            "Episode 1.5 Interview.mp3"
+        );
+    }
+}
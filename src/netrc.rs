@@ -0,0 +1,193 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for reading credentials from the user's `.netrc` file.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Login name and password for a host.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+}
+
+// The password is left out, so that it does not show up in debug output.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("login", &self.login)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The entries of a `.netrc` file.
+#[derive(Debug, Default)]
+pub struct Netrc {
+    /// Credentials keyed by the (lowercase) host name.
+    machines: HashMap<String, Credentials>,
+}
+
+impl Netrc {
+    /// Parse the contents of a `.netrc` file.
+    ///
+    /// Macro definitions (`macdef`) are skipped, as are entries without login or password. The
+    /// `default` entry is ignored too, because episodes are often hosted by third parties that
+    /// should not receive the credentials.
+    pub fn parse(content: &str) -> Self {
+        let mut netrc = Self::default();
+        // The host of the entry that is being parsed.
+        let mut machine: Option<String> = None;
+        let mut login = None;
+        let mut password = None;
+
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" | "macdef" => {
+                        netrc.insert(machine.take(), login.take(), password.take());
+                        match token {
+                            "machine" => machine = tokens.next().map(str::to_lowercase),
+                            "default" => (),
+                            // Macro definitions end with an empty line.
+                            _ => {
+                                for line in lines.by_ref() {
+                                    if line.trim().is_empty() {
+                                        break;
+                                    }
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    "login" => login = tokens.next().map(str::to_owned),
+                    "password" => password = tokens.next().map(str::to_owned),
+                    // Other tokens (e.g. `account`) are not needed. A comment extends until the
+                    // end of the line.
+                    token if token.starts_with('#') => break,
+                    _ => (),
+                }
+            }
+        }
+        netrc.insert(machine, login, password);
+        netrc
+    }
+
+    /// Add the credentials of an entry (if the entry is complete).
+    fn insert(&mut self, host: Option<String>, login: Option<String>, password: Option<String>) {
+        if let (Some(host), Some(login), Some(password)) = (host, login, password) {
+            // As in other tools, the first entry for a host wins.
+            self.machines
+                .entry(host)
+                .or_insert(Credentials { login, password });
+        }
+    }
+
+    /// Load the `.netrc` file from the location in `NETRC` or the home directory.
+    ///
+    /// A missing file results in an empty `Netrc`.
+    pub fn load() -> std::io::Result<Self> {
+        let path = match std::env::var_os("NETRC") {
+            Some(path) => PathBuf::from(path),
+            None => match dirs::home_dir() {
+                Some(home_dir) => home_dir.join(if cfg!(windows) { "_netrc" } else { ".netrc" }),
+                None => return Ok(Self::default()),
+            },
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the credentials for `host` (if any).
+    pub fn credentials(&self, host: &str) -> Option<&Credentials> {
+        self.machines.get(&host.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the login and password for `host` in `netrc`.
+    fn credentials<'a>(netrc: &'a Netrc, host: &str) -> Option<(&'a str, &'a str)> {
+        netrc
+            .credentials(host)
+            .map(|credentials| (credentials.login.as_str(), credentials.password.as_str()))
+    }
+
+    #[test]
+    fn test_parse_multiline_and_single_line_entries() {
+        let netrc = Netrc::parse(
+            "machine feeds.example.com\n  login alice\n  password secret\n\
+             machine other.example.com login bob password hunter2\n",
+        );
+        assert_eq!(
+            credentials(&netrc, "feeds.example.com"),
+            Some(("alice", "secret"))
+        );
+        assert_eq!(
+            credentials(&netrc, "other.example.com"),
+            Some(("bob", "hunter2"))
+        );
+        assert_eq!(credentials(&netrc, "unknown.example.com"), None);
+    }
+
+    #[test]
+    fn test_parse_host_is_case_insensitive() {
+        let netrc = Netrc::parse("machine Feeds.Example.COM login alice password secret");
+        assert_eq!(
+            credentials(&netrc, "FEEDS.example.com"),
+            Some(("alice", "secret"))
+        );
+    }
+
+    #[test]
+    fn test_parse_first_entry_wins() {
+        let netrc = Netrc::parse(
+            "machine example.com login alice password first\n\
+             machine example.com login bob password second\n",
+        );
+        assert_eq!(credentials(&netrc, "example.com"), Some(("alice", "first")));
+    }
+
+    #[test]
+    fn test_parse_skips_incomplete_default_and_comments() {
+        let netrc = Netrc::parse(
+            "# machine commented.example.com login eve password x\n\
+             machine incomplete.example.com login alice\n\
+             machine account.example.com login bob account acme password secret # comment\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(credentials(&netrc, "commented.example.com"), None);
+        assert_eq!(credentials(&netrc, "incomplete.example.com"), None);
+        assert_eq!(
+            credentials(&netrc, "account.example.com"),
+            Some(("bob", "secret"))
+        );
+        assert_eq!(netrc.machines.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_skips_macdef() {
+        let netrc = Netrc::parse(
+            "macdef init\nmachine macro.example.com login eve password x\n\n\
+             machine example.com login alice password secret\n",
+        );
+        assert_eq!(credentials(&netrc, "macro.example.com"), None);
+        assert_eq!(
+            credentials(&netrc, "example.com"),
+            Some(("alice", "secret"))
+        );
+    }
+}
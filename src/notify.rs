@@ -0,0 +1,165 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for reporting the results of a sync (e.g. as notification).
+
+use crate::config::NotifyConfig;
+use crate::download::unix_timestamp;
+use crate::trace;
+use reqwest::Client;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// An episode that has been downloaded during a sync.
+#[derive(Debug, Serialize)]
+pub struct DownloadedEpisode {
+    pub feed_url: String,
+    pub guid: String,
+    /// Title of the podcast that the episode belongs to.
+    pub podcast_title: String,
+    /// Title of the episode.
+    pub title: Option<String>,
+    /// Path of the downloaded file.
+    pub file_path: PathBuf,
+    /// Size of the downloaded file (in bytes).
+    pub bytes: u64,
+    /// Time that the download took (in seconds).
+    pub duration_secs: f64,
+}
+
+/// An episode whose download failed during a sync.
+#[derive(Debug, Serialize)]
+pub struct FailedEpisode {
+    pub feed_url: String,
+    pub guid: String,
+    /// Title of the podcast that the episode belongs to.
+    pub podcast_title: String,
+    /// Title of the episode.
+    pub title: Option<String>,
+    /// Description of the error.
+    pub error: String,
+}
+
+/// Summary of a sync.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    /// Episodes that have been downloaded successfully.
+    pub downloaded: Vec<DownloadedEpisode>,
+    /// Number of failed downloads.
+    pub failed: usize,
+    /// Episodes whose download failed.
+    pub failures: Vec<FailedEpisode>,
+    /// Number of episodes that were skipped, because they have already been downloaded or exceed
+    /// the total episode limit.
+    pub skipped: usize,
+    /// The downloads were stopped, because the free space dropped below `min_free_space`.
+    pub out_of_space: bool,
+}
+
+impl SyncReport {
+    /// Returns `true` if nothing has been downloaded or attempted to download.
+    pub fn is_empty(&self) -> bool {
+        self.downloaded.is_empty() && self.failed == 0
+    }
+
+    /// Write the report as JSON to `path`, together with the time of writing.
+    ///
+    /// Missing parent directories are created. The report is written to a temporary file first,
+    /// so that an existing report is never left half-written.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        #[derive(Serialize)]
+        struct ReportFile<'a> {
+            /// Time at which the report was written (as UNIX timestamp).
+            timestamp: u64,
+            #[serde(flatten)]
+            report: &'a SyncReport,
+        }
+
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(&ReportFile {
+            timestamp: unix_timestamp(),
+            report: self,
+        })?;
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(temp_path, path)
+    }
+
+    /// Returns a human-readable summary of the downloaded episodes.
+    fn summary(&self) -> String {
+        let mut lines = vec![format!("Downloaded {} episodes.", self.downloaded.len())];
+        if self.failed > 0 {
+            lines.push(format!("{} downloads failed.", self.failed));
+        }
+        lines.extend(self.downloaded.iter().map(|episode| {
+            let title = episode.title.as_deref().unwrap_or_else(|| {
+                episode
+                    .file_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or_default()
+            });
+            format!("[{}] {}", episode.podcast_title, title)
+        }));
+        lines.join("\n")
+    }
+}
+
+/// Send the configured notifications for the `report`.
+///
+/// A failing notification does not prevent the others from being sent, and all errors are
+/// returned.
+pub async fn send_notifications(
+    client: &Client,
+    config: &NotifyConfig,
+    report: &SyncReport,
+) -> Vec<Box<dyn std::error::Error>> {
+    let mut errors = Vec::new();
+    if config.desktop.unwrap_or(false) {
+        let body = report.summary();
+        let result = tokio::task::spawn_blocking(move || {
+            notify_rust::Notification::new()
+                .summary("podcatcher-rs")
+                .body(&body)
+                .show()
+                .map(|_| ())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => errors.push(err.into()),
+            Err(err) => errors.push(err.into()),
+        }
+    }
+
+    if let Some(webhook_url) = &config.webhook_url {
+        // Unwrap is safe here, because the report only contains serializable values.
+        let body = serde_json::to_string(report).unwrap();
+        let request = client
+            .post(webhook_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        let result = trace::send(request)
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(err) = result {
+            errors.push(err.into());
+        }
+    }
+    errors
+}
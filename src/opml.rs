@@ -0,0 +1,77 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for reading subscription lists in the OPML format.
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+/// Returns the value of the attribute with the given (case-insensitive) `name`.
+fn attribute(
+    element: &BytesStart<'_>,
+    reader: &Reader<&[u8]>,
+    name: &str,
+) -> quick_xml::Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if attribute.key.eq_ignore_ascii_case(name.as_bytes()) {
+            return attribute.unescape_and_decode_value(reader).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Parse the feeds (given as feed URL and optional title) from an OPML document.
+///
+/// Outlines without an `xmlUrl` attribute (e.g. categories) are skipped, but the feeds nested
+/// in them are included.
+pub fn parse_opml(content: &str) -> quick_xml::Result<Vec<(String, Option<String>)>> {
+    let mut reader = Reader::from_str(content);
+    let mut buf = Vec::new();
+    let mut feeds = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().eq_ignore_ascii_case(b"outline") =>
+            {
+                if let Some(feed_url) = attribute(&element, &reader, "xmlUrl")? {
+                    let title = match attribute(&element, &reader, "title")? {
+                        Some(title) => Some(title),
+                        None => attribute(&element, &reader, "text")?,
+                    };
+                    feeds.push((
+                        feed_url.trim().to_owned(),
+                        title
+                            .map(|title| title.trim().to_owned())
+                            .filter(|title| !title.is_empty()),
+                    ));
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(feeds)
+}
+
+/// Normalize a feed URL for comparing subscriptions from different apps.
+///
+/// The scheme, a trailing slash and the fragment are ignored, and the host is compared
+/// case-insensitively.
+pub fn normalize_feed_url(feed_url: &str) -> String {
+    match reqwest::Url::parse(feed_url.trim()) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            let url = url.as_str();
+            let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+            url.trim_end_matches('/').to_owned()
+        }
+        Err(_) => feed_url.trim().to_owned(),
+    }
+}
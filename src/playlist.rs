@@ -0,0 +1,68 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for writing playlists of downloaded episodes.
+
+use crate::state::{EpisodeState, FeedState};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Render an extended M3U playlist of the given episodes.
+///
+/// The episodes are sorted chronologically (oldest first), and their paths are written relative
+/// to `podcast_dir`.
+fn render_playlist(mut episodes: Vec<&EpisodeState>, podcast_dir: &Path) -> String {
+    episodes.sort_by_key(|episode| episode.pub_date);
+
+    let mut playlist = String::from("#EXTM3U\n");
+    for episode in episodes {
+        let path = episode
+            .file_path
+            .strip_prefix(podcast_dir)
+            .unwrap_or(&episode.file_path);
+        let title = episode
+            .title
+            .as_deref()
+            .or_else(|| path.file_stem().and_then(|stem| stem.to_str()))
+            .unwrap_or_default();
+        let duration = episode.duration.map_or(-1, |duration| duration as i64);
+        // Writing to a String cannot fail.
+        let _ = writeln!(playlist, "#EXTINF:{},{}", duration, title);
+        let _ = writeln!(playlist, "{}", path.display());
+    }
+    playlist
+}
+
+/// Write an `.m3u8` playlist into each directory of the feed that contains downloaded episodes.
+///
+/// Files that no longer exist are left out. The playlist is named after the podcast directory.
+pub fn write_playlists(download_dir: &Path, feed: &FeedState) -> std::io::Result<()> {
+    let mut podcast_dirs: BTreeMap<PathBuf, Vec<&EpisodeState>> = BTreeMap::new();
+    for episode in feed.episodes.values() {
+        if !download_dir.join(&episode.file_path).is_file() {
+            continue;
+        }
+
+        let podcast_dir = match episode.file_path.components().next() {
+            Some(component) => PathBuf::from(component.as_os_str()),
+            None => continue,
+        };
+        podcast_dirs.entry(podcast_dir).or_default().push(episode);
+    }
+
+    for (podcast_dir, episodes) in podcast_dirs {
+        let playlist = render_playlist(episodes, &podcast_dir);
+        let mut path = download_dir.join(&podcast_dir);
+        let file_name = format!("{}.m3u8", podcast_dir.display());
+        path.push(file_name);
+        std::fs::write(path, playlist)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for saving the planned downloads of a sync, so that an interrupted sync can be resumed
+//! without fetching the feeds again.
+
+use crate::context::AppContext;
+use crate::download::{add_netrc_credentials, EpisodeDownload, Sidecar, Throttle};
+use crate::extension::Integrity;
+use crate::metadata::EpisodeMeta;
+use crate::state::State;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the queue file inside the state directory.
+const QUEUE_FILE_NAME: &str = "queue.json";
+
+/// Name of the temporary file that is used while writing the queue file.
+const TEMP_QUEUE_FILE_NAME: &str = "queue.json.tmp";
+
+/// The downloads that have been planned by a sync.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SavedQueue {
+    /// Time when the downloads were planned (as UNIX timestamp).
+    pub created: u64,
+    pub episodes: Vec<QueuedEpisode>,
+}
+
+/// Represents a single planned download.
+///
+/// The custom headers, the bandwidth limit and the proxy are not saved, but are taken from the
+/// config when the queue is resumed, so that no credentials end up in the state directory.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QueuedEpisode {
+    pub feed_url: String,
+    pub feed_index: usize,
+    pub podcast_title: String,
+    pub guid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pub_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<Integrity>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirror_urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<usize>,
+    pub file_path: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<QueuedSidecar>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<EpisodeMeta>,
+}
+
+/// Represents an additional file of a planned download.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QueuedSidecar {
+    pub kind: String,
+    pub url: String,
+    pub extension: String,
+}
+
+/// Returns the kind of a sidecar as used by [`Sidecar`] (or `None` if it is unknown).
+fn sidecar_kind(kind: &str) -> Option<&'static str> {
+    match kind {
+        "chapters" => Some("chapters"),
+        "transcript" => Some("transcript"),
+        _ => None,
+    }
+}
+
+impl SavedQueue {
+    /// Create a queue from the planned `downloads`.
+    pub fn new(downloads: &[EpisodeDownload]) -> Self {
+        let episodes = downloads
+            .iter()
+            .map(|dl| QueuedEpisode {
+                feed_url: dl.feed_url.clone(),
+                feed_index: dl.feed_index,
+                podcast_title: dl.podcast_title.clone(),
+                guid: dl.guid.clone(),
+                title: dl.title.clone(),
+                pub_date: dl.pub_date,
+                duration: dl.duration,
+                url: dl.url.to_string(),
+                integrity: dl.integrity.clone(),
+                mirror_urls: dl.mirror_urls.iter().map(Url::to_string).collect(),
+                file_size: dl.file_size,
+                file_path: dl.file_path.clone(),
+                sidecars: dl
+                    .sidecars
+                    .iter()
+                    .map(|sidecar| QueuedSidecar {
+                        kind: sidecar.kind.to_owned(),
+                        url: sidecar.url.to_string(),
+                        extension: sidecar.extension.clone(),
+                    })
+                    .collect(),
+                replaces: dl.replaces.clone(),
+                metadata: dl.metadata.clone(),
+            })
+            .collect();
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        Self { created, episodes }
+    }
+
+    /// Load the saved queue from the given state directory (if there is one).
+    pub fn load(state_dir: &Path) -> std::io::Result<Option<Self>> {
+        let path = state_dir.join(QUEUE_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Write the queue to the given state directory, replacing a previously saved queue.
+    ///
+    /// Like the state file, the queue is written to a temporary file first, so that it is never
+    /// left half-written.
+    pub fn save(&self, state_dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(state_dir)?;
+        let content = serde_json::to_string_pretty(self)?;
+        let temp_path = state_dir.join(TEMP_QUEUE_FILE_NAME);
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(temp_path, state_dir.join(QUEUE_FILE_NAME))
+    }
+
+    /// Delete the saved queue from the given state directory (if there is one).
+    pub fn remove(state_dir: &Path) -> std::io::Result<()> {
+        match std::fs::remove_file(state_dir.join(QUEUE_FILE_NAME)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the downloads of the queue that have not been finished yet.
+    ///
+    /// An episode is finished if the `state` records it as downloaded to the same file and the
+    /// file exists. Episodes of podcasts that have been removed from the config are left out.
+    pub fn into_downloads(self, context: &AppContext, state: &State) -> Vec<EpisodeDownload> {
+        let config = &context.config;
+        // The bandwidth limit is shared by all episodes of a podcast.
+        let mut throttles: HashMap<String, Option<Arc<Throttle>>> = HashMap::new();
+        self.episodes
+            .into_iter()
+            .filter_map(|episode| {
+                let podcast = config
+                    .podcast
+                    .iter()
+                    .find(|podcast| podcast.feed_url == episode.feed_url)?;
+                let finished =
+                    state
+                        .episode(&episode.feed_url, &episode.guid)
+                        .is_some_and(|downloaded| {
+                            config.download_dir.join(&downloaded.file_path) == episode.file_path
+                        });
+                if finished && episode.file_path.exists() {
+                    return None;
+                }
+
+                let url = Url::parse(&episode.url).ok()?;
+                // The headers have been validated when loading the config.
+                let mut headers = podcast.header_map().unwrap_or_default();
+                add_netrc_credentials(&mut headers, context.netrc.as_ref(), &url);
+                let throttle = throttles
+                    .entry(episode.feed_url.clone())
+                    .or_insert_with(|| {
+                        podcast
+                            .max_bandwidth_kbps
+                            .map(|kbps| Arc::new(Throttle::from_kbps(kbps)))
+                    })
+                    .clone();
+                Some(EpisodeDownload {
+                    feed_url: episode.feed_url,
+                    feed_index: episode.feed_index,
+                    podcast_title: episode.podcast_title,
+                    headers,
+                    guid: episode.guid,
+                    title: episode.title,
+                    pub_date: episode.pub_date,
+                    duration: episode.duration,
+                    url,
+                    integrity: episode.integrity,
+                    mirror_urls: episode
+                        .mirror_urls
+                        .iter()
+                        .filter_map(|url| Url::parse(url).ok())
+                        .collect(),
+                    file_size: episode.file_size,
+                    file_path: episode.file_path,
+                    sidecars: episode
+                        .sidecars
+                        .into_iter()
+                        .filter_map(|sidecar| {
+                            Some(Sidecar {
+                                kind: sidecar_kind(&sidecar.kind)?,
+                                url: Url::parse(&sidecar.url).ok()?,
+                                extension: sidecar.extension,
+                            })
+                        })
+                        .collect(),
+                    replaces: episode.replaces,
+                    metadata: episode.metadata,
+                    throttle,
+                    proxy: podcast.proxy.clone(),
+                    downloaded: false,
+                })
+            })
+            .collect()
+    }
+}
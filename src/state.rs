@@ -0,0 +1,589 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods used for loading and storing the state that persists between runs.
+
+use crate::config::EpisodeMatch;
+use crate::health::backoff_until;
+use futures::lock::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Name of the state file inside the state directory.
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Name of the temporary file that is used while writing the state file.
+const TEMP_STATE_FILE_NAME: &str = "state.json.tmp";
+
+/// Minimum time between two saves of the state during a sync.
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of fetch results that are kept per feed.
+const FETCH_HISTORY_LEN: usize = 10;
+
+/// Represents the state file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// Start of the last sync in which no feed and no download failed (as UNIX timestamp).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<u64>,
+    /// State of each podcast, keyed by feed URL.
+    #[serde(default)]
+    pub feeds: BTreeMap<String, FeedState>,
+}
+
+impl State {
+    /// Load the state from the given state directory.
+    ///
+    /// If no state file exists yet, an empty state is returned.
+    pub fn load(state_dir: &Path) -> std::io::Result<Self> {
+        let path = state_dir.join(STATE_FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Write the state to the given state directory, creating it if necessary.
+    ///
+    /// The state is written to a temporary file first, which then replaces the state file, so
+    /// that the state file is never left half-written (e.g. if the process is killed).
+    pub fn save(&self, state_dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(state_dir)?;
+        let content = serde_json::to_string_pretty(self)?;
+        let temp_path = state_dir.join(TEMP_STATE_FILE_NAME);
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(temp_path, state_dir.join(STATE_FILE_NAME))
+    }
+
+    /// Returns `true` if the episode with the given `guid` has already been downloaded.
+    pub fn is_downloaded(&self, feed_url: &str, guid: &str) -> bool {
+        self.feeds
+            .get(feed_url)
+            .is_some_and(|feed| feed.episodes.contains_key(guid))
+    }
+
+    /// Returns `true` if a downloaded episode of the feed matches the item with the given enclosure
+    /// `url`, `title` and `pub_date` according to `strategy`, regardless of its GUID.
+    ///
+    /// Items without title or publication date never match by `EpisodeMatch::TitleDate`.
+    pub fn matches_downloaded(
+        &self,
+        feed_url: &str,
+        strategy: EpisodeMatch,
+        url: &str,
+        title: Option<&str>,
+        pub_date: Option<i64>,
+    ) -> bool {
+        let mut episodes = match self.feeds.get(feed_url) {
+            Some(feed) => feed.episodes.values(),
+            None => return false,
+        };
+        match strategy {
+            EpisodeMatch::Guid => false,
+            EpisodeMatch::Url => episodes.any(|episode| episode.url.as_deref() == Some(url)),
+            EpisodeMatch::TitleDate => match (title.map(str::trim), pub_date) {
+                (Some(title), Some(pub_date)) if !title.is_empty() => episodes.any(|episode| {
+                    episode.pub_date == Some(pub_date)
+                        && episode.title.as_deref().map(str::trim) == Some(title)
+                }),
+                _ => false,
+            },
+        }
+    }
+
+    /// Returns the downloaded episode with the given `guid` (if any).
+    pub fn episode(&self, feed_url: &str, guid: &str) -> Option<&EpisodeState> {
+        self.feeds
+            .get(feed_url)
+            .and_then(|feed| feed.episodes.get(guid))
+    }
+
+    /// Returns the feed URL of the downloaded episode with the given `guid` (if any).
+    pub fn find_feed_url(&self, guid: &str) -> Option<&str> {
+        self.feeds
+            .iter()
+            .find(|(_, feed)| feed.episodes.contains_key(guid))
+            .map(|(feed_url, _)| feed_url.as_str())
+    }
+
+    /// Returns the previously downloaded episode with the given `guid` if it has been updated
+    /// since, i.e. if its enclosure URL changed or it has a newer publication date.
+    ///
+    /// Episodes whose files have been deleted on purpose are never returned.
+    pub fn updated_episode(
+        &self,
+        feed_url: &str,
+        guid: &str,
+        url: &str,
+        pub_date: Option<i64>,
+    ) -> Option<&EpisodeState> {
+        self.feeds
+            .get(feed_url)
+            .and_then(|feed| feed.episodes.get(guid))
+            .filter(|episode| !episode.removed)
+            .filter(|episode| {
+                let url_changed = episode.url.as_deref().is_some_and(|old_url| old_url != url);
+                let date_changed = match (episode.pub_date, pub_date) {
+                    (Some(old_date), Some(new_date)) => new_date > old_date,
+                    _ => false,
+                };
+                url_changed || date_changed
+            })
+    }
+
+    /// Record that the downloaded file of the episode with the given `guid` has been deleted on
+    /// purpose, so that it is not downloaded again.
+    pub fn mark_removed(&mut self, feed_url: &str, guid: &str) {
+        if let Some(episode) = self
+            .feeds
+            .get_mut(feed_url)
+            .and_then(|feed| feed.episodes.get_mut(guid))
+        {
+            episode.removed = true;
+        }
+    }
+
+    /// Record that the episodes downloaded to `file_path` (relative to the download directory)
+    /// have been deleted on purpose, so that they are not downloaded again.
+    ///
+    /// Returns `true` if any episode has been changed.
+    pub fn mark_file_removed(&mut self, file_path: &Path) -> bool {
+        let mut changed = false;
+        for episode in self
+            .feeds
+            .values_mut()
+            .flat_map(|feed| feed.episodes.values_mut())
+        {
+            if episode.file_path == file_path && !episode.removed {
+                episode.removed = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Remove the state of all feeds for which `is_subscribed(feed_url)` returns `false`.
+    ///
+    /// Returns the number of removed feeds.
+    pub fn prune_feeds(&mut self, is_subscribed: impl Fn(&str) -> bool) -> usize {
+        let count = self.feeds.len();
+        self.feeds.retain(|feed_url, _| is_subscribed(feed_url));
+        count - self.feeds.len()
+    }
+
+    /// Remove all episodes whose file does not exist in `download_dir` anymore.
+    ///
+    /// Episodes that have been deleted on purpose are kept, so that they are not downloaded again.
+    /// Returns the number of removed episodes.
+    pub fn prune_missing_files(&mut self, download_dir: &Path) -> usize {
+        let mut count = 0;
+        for feed in self.feeds.values_mut() {
+            feed.episodes.retain(|_, episode| {
+                let keep = episode.removed || download_dir.join(&episode.file_path).exists();
+                if !keep {
+                    count += 1;
+                }
+                keep
+            });
+        }
+        count
+    }
+
+    /// Returns `true` if the feed has been fetched recently and its update interval (as announced
+    /// by the feed) has not elapsed yet.
+    pub fn is_fresh(&self, feed_url: &str, now: u64) -> bool {
+        self.feeds.get(feed_url).is_some_and(|feed| {
+            match (feed.last_fetched, feed.update_interval) {
+                (Some(last_fetched), Some(interval)) => last_fetched.saturating_add(interval) > now,
+                _ => false,
+            }
+        })
+    }
+
+    /// Returns the time until which the feed should not be fetched, because its last fetches
+    /// failed (see [`backoff_until`]).
+    pub fn backoff_until(&self, feed_url: &str) -> Option<u64> {
+        self.feeds
+            .get(feed_url)
+            .and_then(|feed| backoff_until(&feed.history))
+    }
+
+    /// Record that the feed has been fetched successfully at `now`.
+    pub fn record_fetch(&mut self, feed_url: &str, now: u64, update_interval: Option<u64>) {
+        let feed = self.feeds.entry(feed_url.to_owned()).or_default();
+        feed.last_fetched = Some(now);
+        feed.update_interval = update_interval;
+    }
+
+    /// Append the result of a feed fetch to the feed's history, dropping the oldest results.
+    pub fn record_fetch_result(&mut self, feed_url: &str, result: FetchResult) {
+        let history = &mut self.feeds.entry(feed_url.to_owned()).or_default().history;
+        history.push_back(result);
+        while history.len() > FETCH_HISTORY_LEN {
+            history.pop_front();
+        }
+    }
+
+    /// Store the GUIDs of all items that are currently in the feed and return the GUIDs from the
+    /// previous sync (or `None` if they have never been recorded for this feed).
+    pub fn record_guids(
+        &mut self,
+        feed_url: &str,
+        guids: BTreeSet<String>,
+    ) -> Option<BTreeSet<String>> {
+        self.feeds
+            .entry(feed_url.to_owned())
+            .or_default()
+            .seen_guids
+            .replace(guids)
+    }
+
+    /// Record that the episode with the given `guid` has been downloaded.
+    pub fn mark_downloaded(&mut self, feed_url: &str, guid: &str, episode: EpisodeState) {
+        let feed = self.feeds.entry(feed_url.to_owned()).or_default();
+        feed.pending.remove(guid);
+        feed.episodes.insert(guid.to_owned(), episode);
+    }
+
+    /// Returns `true` if the feed has been fetched successfully since `timestamp` (and did not
+    /// fail afterwards).
+    pub fn fetched_since(&self, feed_url: &str, timestamp: u64) -> bool {
+        self.feeds
+            .get(feed_url)
+            .and_then(|feed| feed.history.back())
+            .is_some_and(|fetch| fetch.timestamp >= timestamp && fetch.success)
+    }
+
+    /// Point all episodes that have been downloaded to `from` to `to` instead (both relative to
+    /// the download directory).
+    ///
+    /// Returns `true` if any episode has been changed.
+    pub fn relink_file(&mut self, from: &Path, to: &Path) -> bool {
+        let mut changed = false;
+        for episode in self
+            .feeds
+            .values_mut()
+            .flat_map(|feed| feed.episodes.values_mut())
+        {
+            if episode.file_path == from {
+                episode.file_path = to.to_owned();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Returns the episode with the given `guid` if it has been found, but not downloaded yet.
+    pub fn pending_episode(&self, feed_url: &str, guid: &str) -> Option<&PendingEpisode> {
+        self.feeds.get(feed_url)?.pending.get(guid)
+    }
+
+    /// Remove the pending episodes of the feeds that have been fetched since `timestamp`, unless
+    /// `keep(feed_url, guid)` returns `true`.
+    pub fn prune_pending(&mut self, timestamp: u64, keep: impl Fn(&str, &str) -> bool) {
+        let fetched: Vec<String> = self
+            .feeds
+            .keys()
+            .filter(|feed_url| self.fetched_since(feed_url, timestamp))
+            .cloned()
+            .collect();
+        for feed_url in fetched {
+            if let Some(feed) = self.feeds.get_mut(&feed_url) {
+                feed.pending.retain(|guid, _| keep(&feed_url, guid));
+            }
+        }
+    }
+
+    /// Record that the episode with the given `guid` has been found, but not downloaded yet.
+    pub fn mark_pending(&mut self, feed_url: &str, guid: &str, episode: PendingEpisode) {
+        self.feeds
+            .entry(feed_url.to_owned())
+            .or_default()
+            .pending
+            .insert(guid.to_owned(), episode);
+    }
+}
+
+/// Shares the state between concurrent downloads and saves it while the downloads are running.
+///
+/// Downloaded episodes are saved at most every [`SAVE_INTERVAL`], so that the progress is not lost
+/// if the process is killed, without rewriting the state file after each download.
+#[derive(Debug)]
+pub struct StateStore {
+    state_dir: PathBuf,
+    inner: Mutex<(State, Instant)>,
+}
+
+impl StateStore {
+    /// Create a new store that saves the `state` to `state_dir`.
+    pub fn new(state: State, state_dir: &Path) -> Self {
+        Self {
+            state_dir: state_dir.to_owned(),
+            inner: Mutex::new((state, Instant::now())),
+        }
+    }
+
+    /// Record that the episode with the given `guid` has been downloaded and save the state if
+    /// it has not been saved recently.
+    pub async fn mark_downloaded(
+        &self,
+        feed_url: &str,
+        guid: &str,
+        episode: EpisodeState,
+    ) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let (state, last_saved) = &mut *inner;
+        state.mark_downloaded(feed_url, guid, episode);
+        if last_saved.elapsed() >= SAVE_INTERVAL {
+            state.save(&self.state_dir)?;
+            *last_saved = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Lock the state, e.g. for the final save after all downloads have finished.
+    pub async fn lock(&self) -> StateGuard<'_> {
+        StateGuard(self.inner.lock().await)
+    }
+}
+
+/// Exclusive access to the state of a [`StateStore`].
+#[derive(Debug)]
+pub struct StateGuard<'a>(MutexGuard<'a, (State, Instant)>);
+
+impl std::ops::Deref for StateGuard<'_> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.0 .0
+    }
+}
+
+impl std::ops::DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.0 .0
+    }
+}
+
+/// Represents the state of a single podcast.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FeedState {
+    /// Time of the last successful fetch (as UNIX timestamp).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_fetched: Option<u64>,
+    /// Update interval announced by the feed (in seconds).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_interval: Option<u64>,
+    /// Results of the most recent fetches (oldest first).
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub history: VecDeque<FetchResult>,
+    /// GUIDs of all items that were in the feed during the last sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seen_guids: Option<BTreeSet<String>>,
+    /// Downloaded episodes, keyed by GUID.
+    #[serde(default)]
+    pub episodes: BTreeMap<String, EpisodeState>,
+    /// Episodes that have been found by `sync --resolve-only`, but not downloaded yet, keyed by
+    /// GUID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending: BTreeMap<String, PendingEpisode>,
+}
+
+/// Represents an episode that is known, but has not been downloaded yet.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingEpisode {
+    /// Enclosure URL of the episode.
+    pub url: String,
+    /// Episode title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Publication date (as UNIX timestamp).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pub_date: Option<i64>,
+    /// Size of the file (in bytes), as reported by the server or the feed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<usize>,
+}
+
+/// Represents the result of a single feed fetch.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FetchResult {
+    /// Time of the fetch (as UNIX timestamp).
+    pub timestamp: u64,
+    /// The feed has been fetched and parsed successfully.
+    pub success: bool,
+    /// HTTP status code of a failed request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Error message of a failed fetch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Number of items in the feed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<usize>,
+    /// Publication date of the newest item in the feed (as UNIX timestamp).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest_pub_date: Option<i64>,
+}
+
+/// Represents the state of a single downloaded episode.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EpisodeState {
+    /// Path of the downloaded file.
+    ///
+    /// This is relative to the download directory, so that the state stays valid when the library
+    /// is moved.
+    pub file_path: PathBuf,
+    /// Enclosure URL that the file was downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Episode title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Publication date (as UNIX timestamp).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pub_date: Option<i64>,
+    /// Duration of the episode (in seconds).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// SHA-256 hash of the downloaded file (if `verify_hashes` is enabled).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The file has been deleted on purpose (e.g. to enforce the library size limit).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_fresh_within_update_interval() {
+        let mut state = State::default();
+        state.record_fetch("https://example.com/feed.xml", 1000, Some(3600));
+        assert!(state.is_fresh("https://example.com/feed.xml", 1000));
+        assert!(state.is_fresh("https://example.com/feed.xml", 4599));
+        assert!(!state.is_fresh("https://example.com/feed.xml", 4600));
+        assert!(!state.is_fresh("https://example.com/other.xml", 1000));
+    }
+
+    #[test]
+    fn is_fresh_without_update_interval() {
+        let mut state = State::default();
+        state.record_fetch("https://example.com/feed.xml", 1000, None);
+        assert!(!state.is_fresh("https://example.com/feed.xml", 1000));
+    }
+
+    #[test]
+    fn is_fresh_with_huge_update_interval() {
+        let mut state = State::default();
+        state.record_fetch("https://example.com/feed.xml", 1000, Some(u64::MAX));
+        assert!(state.is_fresh("https://example.com/feed.xml", u64::MAX - 1));
+    }
+
+    fn downloaded_episode(url: &str, title: &str, pub_date: i64) -> EpisodeState {
+        EpisodeState {
+            file_path: PathBuf::from("Podcast/episode.mp3"),
+            url: Some(url.to_owned()),
+            title: Some(title.to_owned()),
+            pub_date: Some(pub_date),
+            duration: None,
+            sha256: None,
+            removed: false,
+        }
+    }
+
+    #[test]
+    fn matches_downloaded_with_changed_guid() {
+        let feed_url = "https://example.com/feed.xml";
+        let url = "https://example.com/episode.mp3";
+        let mut state = State::default();
+        state.mark_downloaded(
+            feed_url,
+            "old-guid",
+            downloaded_episode(url, "Episode 1", 1000),
+        );
+        let matches = |strategy, url, title, pub_date| {
+            state.matches_downloaded(feed_url, strategy, url, title, pub_date)
+        };
+
+        assert!(!matches(EpisodeMatch::Guid, url, None, None));
+        assert!(matches(EpisodeMatch::Url, url, None, None));
+        assert!(!matches(
+            EpisodeMatch::Url,
+            "https://example.com/other.mp3",
+            Some("Episode 1"),
+            Some(1000)
+        ));
+        assert!(matches(
+            EpisodeMatch::TitleDate,
+            "https://cdn.example.com/episode.mp3",
+            Some(" Episode 1 "),
+            Some(1000)
+        ));
+        assert!(!matches(
+            EpisodeMatch::TitleDate,
+            url,
+            Some("Episode 1"),
+            Some(2000)
+        ));
+        assert!(!matches(EpisodeMatch::TitleDate, url, None, Some(1000)));
+        assert!(!state.matches_downloaded(
+            "https://example.com/other.xml",
+            EpisodeMatch::Url,
+            url,
+            None,
+            None
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_mark_downloaded_persists_all_episodes() {
+        let state_dir = crate::test_server::test_dir("concurrent-mark-downloaded");
+        let feed_url = "https://example.com/feed.xml";
+        let store = std::sync::Arc::new(StateStore {
+            state_dir: state_dir.clone(),
+            // The state has not been saved recently, so the first download saves it right away.
+            inner: Mutex::new((State::default(), Instant::now() - SAVE_INTERVAL)),
+        });
+        let tasks: Vec<_> = (0..32)
+            .map(|i| {
+                let store = std::sync::Arc::clone(&store);
+                tokio::spawn(async move {
+                    let episode =
+                        downloaded_episode(&format!("https://example.com/{}.mp3", i), "", 0);
+                    store
+                        .mark_downloaded(feed_url, &format!("guid-{}", i), episode)
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        // The intermediate save is complete and valid.
+        let saved = State::load(&state_dir).unwrap();
+        assert!(!saved.feeds[feed_url].episodes.is_empty());
+
+        store.lock().await.save(&state_dir).unwrap();
+        let saved = State::load(&state_dir).unwrap();
+        assert_eq!(saved.feeds[feed_url].episodes.len(), 32);
+        assert!((0..32).all(|i| saved.is_downloaded(feed_url, &format!("guid-{}", i))));
+        assert!(!state_dir.join(TEMP_STATE_FILE_NAME).exists());
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for rendering the list of pending downloads.
+
+use crate::download::{to_human_size, EpisodeDownload};
+use colored::Colorize;
+use std::fmt::Write;
+
+/// Output format of the `status` command.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StatusFormat {
+    /// Human-readable table with aligned columns.
+    Table,
+    /// JSON object for scripts.
+    Json,
+    /// Comma-separated values for spreadsheets.
+    Csv,
+}
+
+/// Returns the total size of all pending downloads, and whether the size of some files is
+/// unknown.
+pub fn total_download_size(downloads: &[EpisodeDownload]) -> (usize, bool) {
+    downloads
+        .iter()
+        .filter(|download| !download.downloaded)
+        .fold((0, false), |(total_size, is_partial), download| {
+            let size = download.file_size.unwrap_or(0);
+            (total_size + size, is_partial || size == 0)
+        })
+}
+
+/// Returns a human-readable summary of the total download size.
+///
+/// The summary is highlighted if the size of some files is unknown.
+pub fn render_total_size(downloads: &[EpisodeDownload]) -> String {
+    let (total_size, is_partial) = total_download_size(downloads);
+    let (human_size, human_size_suffix) = to_human_size(total_size);
+    if is_partial {
+        format!(
+            "Total Download Size: {}{} (size of some files is unknown)",
+            human_size, human_size_suffix
+        )
+        .yellow()
+        .to_string()
+    } else {
+        format!("Total Download Size: {}{}", human_size, human_size_suffix)
+    }
+}
+
+/// Format the publication date of a download as `YYYY-MM-DD`.
+fn format_date(download: &EpisodeDownload) -> Option<String> {
+    download
+        .pub_date
+        .and_then(|timestamp| chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0))
+        .map(|date| date.format("%Y-%m-%d").to_string())
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Returns the download status of an episode.
+fn download_status(download: &EpisodeDownload) -> &'static str {
+    if download.downloaded {
+        "downloaded"
+    } else {
+        "pending"
+    }
+}
+
+/// Render the table format, with the total size as trailing summary.
+fn render_table(downloads: &[EpisodeDownload], show_status: bool) -> String {
+    if downloads.is_empty() {
+        return String::from("Nothing to do.\n");
+    }
+
+    let mut header = vec!["FILE", "SIZE", "DATE", "PODCAST"];
+    if show_status {
+        header.insert(0, "STATUS");
+    }
+    let rows: Vec<Vec<String>> = downloads
+        .iter()
+        .map(|download| {
+            let mut row = vec![
+                download.file_name().to_owned(),
+                download.human_file_size(),
+                format_date(download).unwrap_or_else(|| String::from("-")),
+                download.podcast_title.clone(),
+            ];
+            if show_status {
+                row.insert(0, download_status(download).to_owned());
+            }
+            row
+        })
+        .collect();
+
+    let mut widths: Vec<_> = header.iter().map(|column| column.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    let header: Vec<_> = header.into_iter().map(String::from).collect();
+    for (i, row) in std::iter::once(&header).chain(&rows).enumerate() {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        // The colors are applied after padding, so that the escape codes don't break the alignment.
+        let line = line.trim_end();
+        // Writing to a String cannot fail.
+        if i == 0 {
+            let _ = writeln!(output, "{}", line.bold());
+        } else {
+            let _ = writeln!(output, "{}", line);
+        }
+    }
+    let _ = writeln!(output);
+    let _ = writeln!(output, "{}", render_total_size(downloads));
+    output
+}
+
+/// Render the CSV format (without a total size row).
+fn render_csv(downloads: &[EpisodeDownload], show_status: bool) -> String {
+    let mut output = String::new();
+    if show_status {
+        output.push_str("status,");
+    }
+    output.push_str("file_name,size,date,podcast,url\n");
+    for download in downloads {
+        let size = download
+            .file_size
+            .map(|size| size.to_string())
+            .unwrap_or_default();
+        // Writing to a String cannot fail.
+        if show_status {
+            let _ = write!(output, "{},", download_status(download));
+        }
+        let _ = writeln!(
+            output,
+            "{},{},{},{},{}",
+            csv_field(download.file_name()),
+            size,
+            format_date(download).unwrap_or_default(),
+            csv_field(&download.podcast_title),
+            csv_field(download.url.as_str()),
+        );
+    }
+    output
+}
+
+/// Render the JSON format, with the total size as separate fields.
+fn render_json(downloads: &[EpisodeDownload], show_status: bool) -> String {
+    let (total_size, is_partial) = total_download_size(downloads);
+    let episodes: Vec<_> = downloads
+        .iter()
+        .map(|download| {
+            let mut episode = serde_json::json!({
+                "file_name": download.file_name(),
+                "size": download.file_size,
+                "date": format_date(download),
+                "podcast": download.podcast_title,
+                "title": download.title,
+                "url": download.url.as_str(),
+            });
+            if show_status {
+                episode["status"] = download_status(download).into();
+            }
+            episode
+        })
+        .collect();
+    let value = serde_json::json!({
+        "episodes": episodes,
+        "total_size": total_size,
+        "total_size_is_partial": is_partial,
+    });
+    format!("{}\n", value)
+}
+
+/// Render the episodes of a single podcast, either as table or as JSON.
+pub fn render_episodes(episodes: &[EpisodeDownload], json: bool) -> String {
+    if json {
+        let episodes: Vec<_> = episodes
+            .iter()
+            .map(|episode| {
+                serde_json::json!({
+                    "guid": episode.guid,
+                    "title": episode.title,
+                    "date": format_date(episode),
+                    "size": episode.file_size,
+                    "status": download_status(episode),
+                    "file_name": episode.file_name(),
+                    "url": episode.url.as_str(),
+                })
+            })
+            .collect();
+        return format!("{}\n", serde_json::Value::from(episodes));
+    }
+
+    if episodes.is_empty() {
+        return String::from("No episodes found.\n");
+    }
+
+    let rows: Vec<[String; 4]> = episodes
+        .iter()
+        .map(|episode| {
+            [
+                download_status(episode).to_owned(),
+                format_date(episode).unwrap_or_else(|| String::from("-")),
+                episode.human_file_size(),
+                episode
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| episode.file_name().to_owned()),
+            ]
+        })
+        .collect();
+    let header = ["STATUS", "DATE", "SIZE", "TITLE"].map(String::from);
+    let mut widths = header.clone().map(|column| column.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for (i, row) in std::iter::once(&header).chain(&rows).enumerate() {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let line = line.trim_end();
+        // Writing to a String cannot fail.
+        if i == 0 {
+            let _ = writeln!(output, "{}", line.bold());
+        } else {
+            let _ = writeln!(output, "{}", line);
+        }
+    }
+    output
+}
+
+/// Render the list of downloads in the given format.
+///
+/// If `show_status` is set, each episode is marked as `pending` or `downloaded`.
+pub fn render_status(
+    downloads: &[EpisodeDownload],
+    format: StatusFormat,
+    show_status: bool,
+) -> String {
+    match format {
+        StatusFormat::Table => render_table(downloads, show_status),
+        StatusFormat::Json => render_json(downloads, show_status),
+        StatusFormat::Csv => render_csv(downloads, show_status),
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for writing ID3 tags to downloaded episodes.
+
+use chrono::{Datelike, Timelike};
+use id3::frame::Comment;
+use id3::{Tag, TagLike, Timestamp, Version};
+use std::path::Path;
+
+/// Metadata of an episode that is written to its ID3 tag.
+#[derive(Debug, Default)]
+pub struct EpisodeTags<'a> {
+    /// Title of the podcast (used as album).
+    pub podcast_title: &'a str,
+    /// Title of the episode.
+    pub title: Option<&'a str>,
+    /// Author of the episode or podcast (used as artist).
+    pub author: Option<&'a str>,
+    /// Publication date (as UNIX timestamp).
+    pub pub_date: Option<i64>,
+    /// Show notes of the episode (used as comment).
+    pub description: Option<&'a str>,
+}
+
+/// Returns `true` if ID3 tags can be written to the file at `path` (i.e. it is an MP3 file).
+pub fn is_taggable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("mp3"))
+}
+
+/// Convert a UNIX timestamp into an ID3 timestamp (in UTC).
+fn id3_timestamp(timestamp: i64) -> Option<Timestamp> {
+    let date = chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)?;
+    Some(Timestamp {
+        year: date.year(),
+        month: u8::try_from(date.month()).ok(),
+        day: u8::try_from(date.day()).ok(),
+        hour: u8::try_from(date.hour()).ok(),
+        minute: u8::try_from(date.minute()).ok(),
+        second: u8::try_from(date.second()).ok(),
+    })
+}
+
+/// Write the `tags` to the MP3 file at `path`.
+///
+/// Frames of an existing tag that are not set by this method (e.g. cover art) are kept. The
+/// modification time of the file is preserved, because it determines which episodes are deleted
+/// first to enforce the library size limit.
+pub fn write_tags(path: &Path, tags: &EpisodeTags<'_>) -> id3::Result<()> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    let mut tag = match Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => Tag::new(),
+        Err(err) => return Err(err),
+    };
+
+    tag.set_album(tags.podcast_title);
+    tag.set_genre("Podcast");
+    if let Some(title) = tags.title {
+        tag.set_title(title);
+    }
+    if let Some(author) = tags.author {
+        tag.set_artist(author);
+    }
+    if let Some(timestamp) = tags.pub_date.and_then(id3_timestamp) {
+        tag.set_date_released(timestamp);
+    }
+    if let Some(description) = tags.description {
+        tag.remove_comment(Some(""), None);
+        tag.add_frame(Comment {
+            lang: String::from("eng"),
+            description: String::new(),
+            text: description.to_owned(),
+        });
+    }
+    tag.write_to_path(path, Version::Id3v24)?;
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified)?;
+    Ok(())
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! A minimal HTTP server that serves fixed responses in tests.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A response that is served for a path.
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// Returns a `200 OK` response with the given `body`.
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    /// Returns a response with the given `status` and an empty body.
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            ..Self::ok("")
+        }
+    }
+}
+
+/// HTTP server on localhost that answers each request with the response that has been
+/// registered for its path (including the query), or with `404 Not Found`.
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    routes: Arc<Mutex<HashMap<String, TestResponse>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+    /// Start the server on a free port.
+    ///
+    /// The server runs until the test's runtime shuts down.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes: Arc<Mutex<HashMap<String, TestResponse>>> = Arc::default();
+        let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+        let (server_routes, server_requests) = (Arc::clone(&routes), Arc::clone(&requests));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(
+                    stream,
+                    Arc::clone(&server_routes),
+                    Arc::clone(&server_requests),
+                ));
+            }
+        });
+        Self {
+            addr,
+            routes,
+            requests,
+        }
+    }
+
+    /// Answer requests for `path` with `response` from now on.
+    pub fn serve(&self, path: &str, response: TestResponse) {
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), response);
+    }
+
+    /// Returns the URL of `path` on this server.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Returns the requests that have been received so far (e.g. `GET /feed.xml`).
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Answer a single request and close the connection.
+async fn handle_connection(
+    mut stream: TcpStream,
+    routes: Arc<Mutex<HashMap<String, TestResponse>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+) {
+    // Request bodies are not needed, so only the head is read.
+    let mut head = Vec::new();
+    let mut buffer = [0; 4096];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => head.extend_from_slice(&buffer[..len]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_owned();
+    let path = request_line.next().unwrap_or_default().to_owned();
+    requests
+        .lock()
+        .unwrap()
+        .push(format!("{} {}", method, path));
+
+    let response = routes
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .unwrap_or_else(|| TestResponse::status(404));
+    let mut response_head = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        response_head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response_head.push_str("\r\n");
+    let _ = stream.write_all(response_head.as_bytes()).await;
+    if method != "HEAD" {
+        let _ = stream.write_all(&response.body).await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Create an empty directory for the test `name`.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "podcatcher-rs-test-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,78 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for logging HTTP requests and responses to stderr (enabled with `--trace-http`).
+
+use crate::config::REDACTED;
+use reqwest::header::{HeaderMap, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE};
+use reqwest::{RequestBuilder, Response, Url};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether requests are logged.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Log all requests that are sent with `send` from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns the `url` with the password and the values of all query parameters redacted, because
+/// private feeds often use them for authentication.
+fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        // Setting the password can only fail for URLs that cannot have a password.
+        let _ = url.set_password(Some(REDACTED));
+    }
+    if url.query().is_some() {
+        let keys: Vec<String> = url.query_pairs().map(|(key, _)| key.into_owned()).collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(keys.iter().map(|key| (key, REDACTED)));
+    }
+    url.to_string()
+}
+
+/// Print the `headers` with the given line `prefix`, redacting credentials.
+///
+/// Besides the well-known authentication headers, this redacts all values that are marked as
+/// sensitive (e.g. the custom headers from the config).
+fn print_headers(prefix: &str, headers: &HeaderMap) {
+    for (name, value) in headers {
+        let is_sensitive = value.is_sensitive()
+            || [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name);
+        let value = if is_sensitive {
+            REDACTED
+        } else {
+            value.to_str().unwrap_or("<binary>")
+        };
+        eprintln!("{} {}: {}", prefix, name, value);
+    }
+}
+
+/// Send the `request` and log it together with the response (if enabled).
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return request.send().await;
+    }
+
+    // Requests without a streaming body can always be cloned.
+    if let Some(Ok(traced)) = request.try_clone().map(RequestBuilder::build) {
+        eprintln!("> {} {}", traced.method(), redact_url(traced.url()));
+        print_headers(">  ", traced.headers());
+    }
+    let result = request.send().await;
+    match &result {
+        Ok(response) => {
+            eprintln!("< {} {}", response.status(), redact_url(response.url()));
+            print_headers("<  ", response.headers());
+        }
+        Err(err) => eprintln!("< Error: {}", err),
+    }
+    result
+}
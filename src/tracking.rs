@@ -0,0 +1,146 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for removing analytics redirectors from enclosure URLs.
+
+use reqwest::Url;
+
+/// Prefixes of common analytics redirectors that are followed by the actual media URL.
+///
+/// Each prefix consists of a host and path segments, where `*` matches a single segment.
+pub const DEFAULT_TRACKING_PREFIXES: &[&str] = &[
+    "chtbl.com/track/*",
+    "chrt.fm/track/*",
+    "dts.podtrac.com/redirect.mp3",
+    "dts.podtrac.com/redirect.m4a",
+    "www.podtrac.com/pts/redirect.mp3",
+    "podtrac.com/pts/redirect.mp3",
+    "pdst.fm/e",
+    "op3.dev/e",
+    "pfx.vpixl.com/*",
+    "mgln.ai/e/*",
+    "arttrk.com/p/*",
+    "pscrb.fm/rss/p",
+    "verifi.podscribe.com/rss/p",
+    "claritaspod.com/measure",
+    "prfx.byspotify.com/e",
+    "tracking.swap.fm/track/*",
+];
+
+/// Returns the part of `url` (without scheme) after `prefix`, if `url` starts with it.
+fn strip_prefix<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut rest = url;
+    for segment in prefix.trim_matches('/').split('/') {
+        let (head, tail) = rest.split_once('/')?;
+        if segment != "*" && !head.eq_ignore_ascii_case(segment) {
+            return None;
+        }
+        rest = tail;
+    }
+    Some(rest)
+}
+
+/// Returns `url` with all leading tracking redirectors that match one of the `prefixes` removed.
+///
+/// Redirectors are often chained, so prefixes are removed until none of them matches. URLs
+/// without a known redirector are returned unchanged.
+pub fn strip_tracking(url: &Url, prefixes: &[&str]) -> Url {
+    let mut url = url.clone();
+    loop {
+        let scheme = url.scheme().to_owned();
+        let without_scheme = match url.as_str().split_once("://") {
+            Some((_, without_scheme)) => without_scheme,
+            None => return url,
+        };
+        let stripped = prefixes
+            .iter()
+            .find_map(|prefix| strip_prefix(without_scheme, prefix))
+            .filter(|rest| !rest.is_empty())
+            .and_then(|rest| {
+                // Some redirectors keep the scheme of the media URL, others drop it.
+                if rest.starts_with("http://") || rest.starts_with("https://") {
+                    Url::parse(rest).ok()
+                } else {
+                    Url::parse(&format!("{}://{}", scheme, rest)).ok()
+                }
+            })
+            .filter(Url::has_host);
+        match stripped {
+            Some(stripped) => url = stripped,
+            None => return url,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Strip the default tracking prefixes from `url`.
+    fn strip(url: &str) -> String {
+        strip_tracking(&Url::parse(url).unwrap(), DEFAULT_TRACKING_PREFIXES).to_string()
+    }
+
+    #[test]
+    fn test_strip_tracking_without_scheme() {
+        assert_eq!(
+            strip("https://chtbl.com/track/ABC123/traffic.example.com/show/ep1.mp3"),
+            "https://traffic.example.com/show/ep1.mp3"
+        );
+        assert_eq!(
+            strip("http://dts.podtrac.com/redirect.mp3/media.example.com/ep1.mp3?x=1"),
+            "http://media.example.com/ep1.mp3?x=1"
+        );
+    }
+
+    #[test]
+    fn test_strip_tracking_with_scheme() {
+        assert_eq!(
+            strip("https://op3.dev/e/https://media.example.com/ep1.mp3"),
+            "https://media.example.com/ep1.mp3"
+        );
+    }
+
+    #[test]
+    fn test_strip_tracking_chained() {
+        assert_eq!(
+            strip("https://pdst.fm/e/chtbl.com/track/XYZ/dts.podtrac.com/redirect.mp3/media.example.com/ep1.mp3"),
+            "https://media.example.com/ep1.mp3"
+        );
+    }
+
+    #[test]
+    fn test_strip_tracking_case_insensitive_host() {
+        assert_eq!(
+            strip("https://CHTBL.com/track/ABC123/media.example.com/ep1.mp3"),
+            "https://media.example.com/ep1.mp3"
+        );
+    }
+
+    #[test]
+    fn test_strip_tracking_unchanged() {
+        for url in [
+            "https://media.example.com/show/ep1.mp3",
+            "https://example.com/chtbl.com/track/ABC123/media.example.com/ep1.mp3",
+            "https://chtbl.com/track/ABC123/",
+            "https://chtbl.com/other/ABC123/media.example.com/ep1.mp3",
+        ] {
+            assert_eq!(strip(url), url);
+        }
+    }
+
+    #[test]
+    fn test_strip_tracking_custom_prefixes() {
+        let url = Url::parse("https://stats.example.org/r/media.example.com/ep1.mp3").unwrap();
+        assert_eq!(
+            strip_tracking(&url, &["stats.example.org/r"]).as_str(),
+            "https://media.example.com/ep1.mp3"
+        );
+        assert_eq!(strip_tracking(&url, &[]), url);
+    }
+}
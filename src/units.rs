@@ -0,0 +1,196 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for parsing human-readable values.
+
+use std::time::Duration;
+
+/// Parse a duration string like `30d`, `12h` or `2w`.
+///
+/// Supported units are `s` (seconds), `m` (minutes), `h` (hours), `d` (days) and `w` (weeks).
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let unit_pos = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("Missing unit in duration: {}", value))?;
+    let (number, unit) = value.split_at(unit_pos);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid number in duration: {}", value))?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid unit in duration: {}", value)),
+    };
+    number
+        .checked_mul(factor)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration is too large: {}", value))
+}
+
+/// Parse a size string like `500M`, `50G` or `1T` into bytes.
+///
+/// Supported units are `B`, `K`, `M`, `G` and `T` (decimal, i.e. `1K` is 1000 bytes). A number
+/// without unit is interpreted as bytes.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let unit_pos = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_pos);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("Invalid number in size: {}", value))?;
+    let factor: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "M" | "MB" => 1000 * 1000,
+        "G" | "GB" => 1000 * 1000 * 1000,
+        "T" | "TB" => 1000 * 1000 * 1000 * 1000,
+        _ => return Err(format!("Invalid unit in size: {}", value)),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Size is too large: {}", value))
+}
+
+/// Parse a date as used in RSS feeds (RFC 2822, or RFC 3339 as fallback) into a UNIX timestamp.
+pub fn parse_date(value: &str) -> Option<i64> {
+    let value = value.trim();
+    chrono::DateTime::parse_from_rfc2822(value)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(value))
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// Parse a selection of list entries like `1 3-5,7`, `all` or `none` into zero-based indices.
+///
+/// Entries are numbered from `1` to `count`. The returned indices are sorted and deduplicated.
+pub fn parse_selection(value: &str, count: usize) -> Result<Vec<usize>, String> {
+    let value = value.trim();
+    match value.to_ascii_lowercase().as_str() {
+        "" | "all" => return Ok((0..count).collect()),
+        "none" => return Ok(Vec::new()),
+        _ => (),
+    }
+
+    let parse_number = |number: &str| -> Result<usize, String> {
+        match number.trim().parse::<usize>() {
+            Ok(number) if (1..=count).contains(&number) => Ok(number - 1),
+            _ => Err(format!("Invalid entry number: {}", number.trim())),
+        }
+    };
+
+    let mut indices = std::collections::BTreeSet::new();
+    for part in value.split(|c: char| c == ',' || c.is_whitespace()) {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse_number(start)?, parse_number(end)?);
+                if start > end {
+                    return Err(format!("Invalid range: {}", part));
+                }
+                indices.extend(start..=end);
+            }
+            None => {
+                indices.insert(parse_number(part)?);
+            }
+        }
+    }
+    Ok(indices.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration_units() {
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration(" 12h "),
+            Ok(Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration_invalid() {
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("30y").is_err());
+        assert!(parse_duration("30D").is_err());
+        assert!(parse_duration("-30d").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn test_parse_size_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("500k"), Ok(500_000));
+        assert_eq!(parse_size("500 MB"), Ok(500_000_000));
+        assert_eq!(parse_size("50G"), Ok(50_000_000_000));
+        assert_eq!(parse_size("1T"), Ok(1_000_000_000_000));
+    }
+
+    #[test]
+    fn test_parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("50X").is_err());
+        assert!(parse_size("50 GiB").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("Tue, 03 May 2022 12:00:00 GMT"),
+            Some(1_651_579_200)
+        );
+        assert_eq!(
+            parse_date(" 2022-05-03T14:00:00+02:00 "),
+            Some(1_651_579_200)
+        );
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(parse_selection("", 3), Ok(vec![0, 1, 2]));
+        assert_eq!(parse_selection("ALL", 3), Ok(vec![0, 1, 2]));
+        assert_eq!(parse_selection("none", 3), Ok(vec![]));
+        assert_eq!(parse_selection("1 3-5,7", 8), Ok(vec![0, 2, 3, 4, 6]));
+        assert_eq!(parse_selection("2,2 1-2", 3), Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn test_parse_selection_invalid() {
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("4", 3).is_err());
+        assert!(parse_selection("3-1", 3).is_err());
+        assert!(parse_selection("1-", 3).is_err());
+        assert!(parse_selection("first", 3).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2022 Jan Holthuis
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0. If a copy
+// of the MPL was not distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Methods for checking if a newer version has been published.
+
+use crate::trace;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Version of the running binary.
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// URL of the crates.io API endpoint that describes this crate.
+const CRATES_IO_URL: &str = concat!("https://crates.io/api/v1/crates/", env!("CARGO_PKG_NAME"));
+
+#[derive(Debug, Deserialize)]
+struct CrateResponse {
+    #[serde(rename = "crate")]
+    krate: CrateInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateInfo {
+    max_stable_version: Option<String>,
+    max_version: String,
+}
+
+/// Extract the latest stable version (or the latest version if there is no stable one) from a
+/// crates.io API response.
+fn parse_latest_version(body: &str) -> serde_json::Result<String> {
+    let response: CrateResponse = serde_json::from_str(body)?;
+    Ok(response
+        .krate
+        .max_stable_version
+        .unwrap_or(response.krate.max_version))
+}
+
+/// Query crates.io for the latest published version.
+pub async fn fetch_latest_version(client: &Client) -> Result<String, Box<dyn std::error::Error>> {
+    // crates.io rejects requests without a user agent.
+    let request = client
+        .get(CRATES_IO_URL)
+        .header(
+            reqwest::header::USER_AGENT,
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        )
+        .timeout(Duration::from_secs(10));
+    let body = trace::send(request)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(parse_latest_version(&body)?)
+}
+
+/// Returns `true` if `latest` is a higher version than `current`.
+///
+/// Versions are compared by their numeric components, pre-release suffixes are ignored.
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    let components = |version: &str| -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|component| component.parse().unwrap_or(0))
+            .collect()
+    };
+    components(latest) > components(current)
+}